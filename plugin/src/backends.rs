@@ -1,70 +1,135 @@
-use burn::backend::{NdArray, Wgpu};
+use burn::backend::{Cpu, NdArray, Wgpu};
 use burn::prelude::{Backend, DeviceOps};
-use log::info;
-use wgpu::{BackendOptions, Backends, Instance, InstanceDescriptor, InstanceFlags, MemoryBudgetThresholds};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use wgpu::{Adapter, BackendOptions, Backends, Instance, InstanceDescriptor, InstanceFlags, MemoryBudgetThresholds};
 
-/// Generic helper that checks whether a backend exposes any devices.
+/// Backend report, probed once per process on first use.
+static BACKEND_REPORT: Lazy<BackendReport> = Lazy::new(probe_backends);
+
+/// What the host can run graphs on.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendReport {
+    /// Every adapter wgpu enumerates, across all graphics APIs.
+    pub adapters: Vec<AdapterReport>,
+    /// Burn backends compiled into the plugin.
+    pub backends: Vec<BurnBackendReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AdapterReport {
+    pub name: String,
+    pub vendor: u32,
+    pub device: u32,
+    pub backend: String,
+    pub device_type: String,
+    pub driver: String,
+    pub limits: AdapterLimits,
+}
+
+/// The adapter limits that bound model and batch sizes.
+#[derive(Debug, Clone, Serialize)]
+pub struct AdapterLimits {
+    pub max_buffer_size: u64,
+    pub max_storage_buffer_binding_size: u32,
+    pub max_storage_buffers_per_shader_stage: u32,
+    pub max_compute_workgroup_storage_size: u32,
+    pub max_compute_invocations_per_workgroup: u32,
+    pub max_compute_workgroups_per_dimension: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BurnBackendReport {
+    pub name: &'static str,
+    pub devices: usize,
+}
+
+/// Counts the devices a backend exposes.
 ///
 /// Many backends provide a `device_count(type_id: u16) -> usize`
 /// method on their `Device` type. Commonly `type_id == 0` is the primary
 /// device-family (e.g. default GPU type). We probe a few type ids to be
 /// robust to backends that distinguish device families.
-fn backend_has_devices<B: Backend>() -> bool {
+fn backend_device_count<B: Backend>() -> usize {
     // How many different type_ids to probe. Increase if you know a backend
     // uses other type_id ranges.
     const MAX_TYPE_IDS: u16 = 4;
 
-    for type_id in 0..MAX_TYPE_IDS {
-        if B::Device::device_count(type_id) > 0 {
-            return true;
-        }
-    }
-
-    false
+    (0..MAX_TYPE_IDS).map(|type_id| B::Device::device_count(type_id)).sum()
 }
 
-pub async fn get_backends() {
-
-    info!("=== WebGPU state:");
-
-    let instance = Instance::new(&InstanceDescriptor {
+fn instance() -> Instance {
+    Instance::new(&InstanceDescriptor {
         backends: Backends::all(),
         flags: InstanceFlags::empty(), // no special flags
         memory_budget_thresholds: MemoryBudgetThresholds::default(), // default memory limits
         backend_options: BackendOptions::default(), // default backend options
-    });
-
-    let adapter = instance
-        .request_adapter(&wgpu::RequestAdapterOptions::default())
-        .await
-        .expect("Failed to find an appropriate adapter");
+    })
+}
 
+fn adapter_report(adapter: &Adapter) -> AdapterReport {
     let info = adapter.get_info();
-    info!("GPU Name: {}", info.name);
-    info!("Vendor ID: {:x}", info.vendor);
-    info!("Device ID: {:x}", info.device);
-    info!("Backend: {:?}", info.backend);
+    let limits = adapter.limits();
+    AdapterReport {
+        name: info.name,
+        vendor: info.vendor,
+        device: info.device,
+        backend: format!("{:?}", info.backend),
+        device_type: format!("{:?}", info.device_type),
+        driver: info.driver,
+        limits: AdapterLimits {
+            max_buffer_size: limits.max_buffer_size,
+            max_storage_buffer_binding_size: limits.max_storage_buffer_binding_size,
+            max_storage_buffers_per_shader_stage: limits.max_storage_buffers_per_shader_stage,
+            max_compute_workgroup_storage_size: limits.max_compute_workgroup_storage_size,
+            max_compute_invocations_per_workgroup: limits.max_compute_invocations_per_workgroup,
+            max_compute_workgroups_per_dimension: limits.max_compute_workgroups_per_dimension,
+        },
+    }
+}
 
-    info!("=== Backend availability (compile-time features + runtime device check):");
+fn probe_backends() -> BackendReport {
+    let adapters: Vec<AdapterReport> = instance()
+        .enumerate_adapters(Backends::all())
+        .iter()
+        .map(adapter_report)
+        .collect();
 
-    // CPU backend (always available if compiled)
-    // Many examples in the repo use NdArray<f32> as the concrete type.
-    info!(
-        "  - NdArray (CPU) -> compiled: yes, devices: {}",
-        backend_has_devices::<NdArray<f32>>()
-    );
+    // (compile-time features + runtime device check)
+    let backends = vec![
+        // Many examples in the repo use NdArray<f32> as the concrete type.
+        BurnBackendReport { name: "ndarray", devices: backend_device_count::<NdArray<f32>>() },
+        // CubeCL JIT CPU backend
+        BurnBackendReport { name: "cpu", devices: backend_device_count::<Cpu>() },
+        // WGPU / WebGPU backends
+        BurnBackendReport { name: "wgpu", devices: backend_device_count::<Wgpu>() },
+    ];
 
-    // WGPU / WebGPU backends
-    info!(
-        "  - WGPU -> compiled: yes, devices: {}",
-        backend_has_devices::<Wgpu>()
-    );
+    let report = BackendReport { adapters, backends };
+    log_report(&report);
+    report
+}
 
-    // CUDA (NVIDIA GPUs)
-    //println!(
-    //    " - CUDA -> compiled: yes, devices: {}",
-    //    backend_has_devices::<burn_cuda::Cuda<f32, i32>>()
-    //);
+fn log_report(report: &BackendReport) {
+    info!("=== WebGPU state:");
+    if report.adapters.is_empty() {
+        warn!("No wgpu adapter found; GPU graphs fall back to the CPU unless the policy is strict");
+    }
+    for adapter in &report.adapters {
+        info!(
+            "  - {} ({} {}, vendor {:x}, device {:x})",
+            adapter.name, adapter.backend, adapter.device_type, adapter.vendor, adapter.device
+        );
+    }
+
+    info!("=== Backend availability:");
+    for backend in &report.backends {
+        info!("  - {} -> devices: {}", backend.name, backend.devices);
+    }
+}
 
-    info!("=== ");
-}
\ No newline at end of file
+/// Returns the backend report, probing adapters on the first call.
+pub fn get_backends() -> &'static BackendReport {
+    &BACKEND_REPORT
+}
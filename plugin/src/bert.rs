@@ -0,0 +1,335 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use burn::module::Module;
+use burn::nn::attention::{MhaInput, MultiHeadAttention, MultiHeadAttentionConfig};
+use burn::nn::transformer::{PositionWiseFeedForward, PositionWiseFeedForwardConfig};
+use burn::nn::{Embedding, EmbeddingConfig, LayerNorm, LayerNormConfig};
+use burn::prelude::Backend;
+use burn::tensor::{Bool, Int, Tensor};
+use burn_import::safetensors::AdapterType;
+use log::{error, info};
+use serde::Deserialize;
+use crate::ErrNo;
+use crate::tokenizer::TextTokenizer;
+use crate::weights::load_safetensors;
+
+/// Key remaps from HuggingFace BERT checkpoints to `BertEncoder` field names.
+const BERT_REMAPS: [(&str, &str); 9] = [
+    ("^bert\\.", ""),
+    ("embeddings\\.LayerNorm", "embeddings.layer_norm"),
+    ("encoder\\.layer\\.([0-9]+)", "layers.$1"),
+    ("attention\\.self\\.(query|key|value)", "attention.$1"),
+    ("attention\\.output\\.LayerNorm", "attention_norm"),
+    ("attention\\.output\\.dense", "attention.output"),
+    ("intermediate\\.dense", "ffn.linear_inner"),
+    ("layers\\.([0-9]+)\\.output\\.dense", "layers.$1.ffn.linear_outer"),
+    ("layers\\.([0-9]+)\\.output\\.LayerNorm", "layers.$1.output_norm"),
+];
+
+/// Subset of a HuggingFace `config.json` needed to build the encoder.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BertConfig {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub intermediate_size: usize,
+    pub max_position_embeddings: usize,
+    pub type_vocab_size: usize,
+    #[serde(default = "default_layer_norm_eps")]
+    pub layer_norm_eps: f64,
+}
+
+fn default_layer_norm_eps() -> f64 {
+    1e-12
+}
+
+#[derive(Module, Debug)]
+pub struct BertEmbeddings<B: Backend> {
+    word_embeddings: Embedding<B>,
+    position_embeddings: Embedding<B>,
+    token_type_embeddings: Embedding<B>,
+    layer_norm: LayerNorm<B>,
+}
+
+#[derive(Module, Debug)]
+pub struct BertLayer<B: Backend> {
+    attention: MultiHeadAttention<B>,
+    attention_norm: LayerNorm<B>,
+    ffn: PositionWiseFeedForward<B>,
+    output_norm: LayerNorm<B>,
+}
+
+#[derive(Module, Debug)]
+pub struct BertEncoder<B: Backend> {
+    embeddings: BertEmbeddings<B>,
+    layers: Vec<BertLayer<B>>,
+}
+
+impl BertConfig {
+    pub fn init<B: Backend>(&self, device: &B::Device) -> BertEncoder<B> {
+        let layer_norm = || LayerNormConfig::new(self.hidden_size).with_epsilon(self.layer_norm_eps);
+
+        let embeddings = BertEmbeddings {
+            word_embeddings: EmbeddingConfig::new(self.vocab_size, self.hidden_size).init(device),
+            position_embeddings: EmbeddingConfig::new(self.max_position_embeddings, self.hidden_size).init(device),
+            token_type_embeddings: EmbeddingConfig::new(self.type_vocab_size, self.hidden_size).init(device),
+            layer_norm: layer_norm().init(device),
+        };
+
+        let layers = (0..self.num_hidden_layers)
+            .map(|_| BertLayer {
+                attention: MultiHeadAttentionConfig::new(self.hidden_size, self.num_attention_heads)
+                    .with_dropout(0.0)
+                    .init(device),
+                attention_norm: layer_norm().init(device),
+                ffn: PositionWiseFeedForwardConfig::new(self.hidden_size, self.intermediate_size)
+                    .with_dropout(0.0)
+                    .init(device),
+                output_norm: layer_norm().init(device),
+            })
+            .collect();
+
+        BertEncoder { embeddings, layers }
+    }
+}
+
+impl<B: Backend> BertEncoder<B> {
+    /// Returns the last hidden state `[batch, seq, hidden]`.
+    pub fn forward(&self, input_ids: Tensor<B, 2, Int>, mask_pad: Tensor<B, 2, Bool>) -> Tensor<B, 3> {
+        let [batch_size, seq_length] = input_ids.dims();
+        let device = input_ids.device();
+
+        let positions = Tensor::<B, 1, Int>::arange(0..seq_length as i64, &device)
+            .unsqueeze::<2>()
+            .repeat_dim(0, batch_size);
+        let token_types = Tensor::<B, 2, Int>::zeros([batch_size, seq_length], &device);
+
+        let embeddings = self.embeddings.word_embeddings.forward(input_ids)
+            + self.embeddings.position_embeddings.forward(positions)
+            + self.embeddings.token_type_embeddings.forward(token_types);
+        let mut hidden = self.embeddings.layer_norm.forward(embeddings);
+
+        for layer in &self.layers {
+            let attention = layer.attention
+                .forward(MhaInput::self_attn(hidden.clone()).mask_pad(mask_pad.clone()))
+                .context;
+            hidden = layer.attention_norm.forward(hidden + attention);
+            let ffn = layer.ffn.forward(hidden.clone());
+            hidden = layer.output_norm.forward(hidden + ffn);
+        }
+
+        hidden
+    }
+}
+
+pub struct BertModel<B: Backend> {
+    encoder: BertEncoder<B>,
+    normalize: bool,
+    max_tokens: usize,
+    vocab_size: usize,
+    tokenizer: Option<Arc<TextTokenizer>>,
+    device: B::Device,
+}
+
+pub struct EmbeddingContext<B: Backend> {
+    pub input_ids: Option<Tensor<B, 2, Int>>,
+    pub attention_mask: Option<Tensor<B, 2, Int>>,
+    pub output: Option<Tensor<B, 2>>,
+    max_tokens: usize,
+    vocab_size: usize,
+    tokenizer: Option<Arc<TextTokenizer>>,
+    device: B::Device,
+}
+
+impl<B: Backend> BertModel<B> {
+    /// Loads a sentence-transformers style model directory holding
+    /// `config.json`, `model.safetensors` and optionally `tokenizer.json`.
+    pub fn new(path: &Path, normalize: bool, device: &B::Device) -> Result<Self, ErrNo> {
+        let config: BertConfig = fs::read_to_string(path.join("config.json"))
+            .map_err(|err| err.to_string())
+            .and_then(|json| serde_json::from_str(&json).map_err(|err| err.to_string()))
+            .map_err(|err| {
+                error!("Failed to read BERT config in {}: {}", path.display(), err);
+                ErrNo::NotFound
+            })?;
+
+        let record = load_safetensors::<B, _>(
+            &path.join("model.safetensors"),
+            &BERT_REMAPS,
+            AdapterType::PyTorch,
+            device,
+        ).map_err(|err| {
+            error!("Failed to load BERT weights in {}: {:?}", path.display(), err);
+            ErrNo::RuntimeError
+        })?;
+
+        info!("Loaded BERT encoder: {} layers, hidden size {}", config.num_hidden_layers, config.hidden_size);
+
+        let encoder = config.init::<B>(device).load_record(record);
+        let tokenizer = TextTokenizer::from_dir(path).map(Arc::new);
+
+        Ok(BertModel {
+            encoder,
+            normalize,
+            max_tokens: config.max_position_embeddings,
+            vocab_size: config.vocab_size,
+            tokenizer,
+            device: device.clone(),
+        })
+    }
+
+    pub fn tokenizer(&self) -> Option<&TextTokenizer> {
+        self.tokenizer.as_deref()
+    }
+
+    pub fn init_execution_context(&self) -> EmbeddingContext<B> {
+        EmbeddingContext {
+            input_ids: None,
+            attention_mask: None,
+            output: None,
+            max_tokens: self.max_tokens,
+            vocab_size: self.vocab_size,
+            tokenizer: self.tokenizer.clone(),
+            device: self.device.clone(),
+        }
+    }
+
+    /// Encodes `[batch, seq]` token ids and returns mean-pooled `[batch, hidden]` embeddings.
+    pub fn compute(&self, input_ids: Tensor<B, 2, Int>, attention_mask: Tensor<B, 2, Int>) -> Tensor<B, 2> {
+        let mask_pad = attention_mask.clone().equal_elem(0);
+        let hidden = self.encoder.forward(input_ids, mask_pad);
+        mean_pool(hidden, attention_mask, self.normalize)
+    }
+}
+
+/// Averages `[batch, seq, hidden]` states over the tokens the mask attends,
+/// L2-normalizing the result if asked to.
+fn mean_pool<B: Backend>(hidden: Tensor<B, 3>, attention_mask: Tensor<B, 2, Int>, normalize: bool) -> Tensor<B, 2> {
+    let mask = attention_mask.float().unsqueeze_dim::<3>(2);
+    let summed = (hidden * mask.clone()).sum_dim(1);
+    // a row without attended tokens pools to zeros instead of NaN
+    let counts = mask.sum_dim(1).clamp_min(1.0);
+    let pooled = (summed / counts).squeeze::<2>(1);
+
+    if normalize {
+        let norm = pooled.clone().powf_scalar(2.0).sum_dim(1).sqrt().clamp_min(1e-12);
+        pooled / norm
+    } else {
+        pooled
+    }
+}
+
+impl<B: Backend> EmbeddingContext<B> {
+
+    /// Index 0 takes `[batch, seq]` token ids below the vocabulary size, index 1
+    /// an attention mask of zeros and ones that attends at least one token per
+    /// row; `seq` is bounded by the position embeddings.
+    pub fn set_tokens(&mut self, index: i32, tokens: &[i64], dimens: [usize; 2]) -> Result<(), ErrNo> {
+        let [batch_size, seq_length] = dimens;
+        if batch_size == 0 || seq_length == 0 || tokens.len() != batch_size * seq_length {
+            error!("Token input {} is empty or does not match its shape {:?}", index, dimens);
+            return Err(ErrNo::InvalidArgument);
+        }
+        if seq_length > self.max_tokens {
+            error!("Sequence of {} tokens exceeds the limit of {}", seq_length, self.max_tokens);
+            return Err(ErrNo::InvalidArgument);
+        }
+        let limit = if index == 0 { self.vocab_size } else { 2 };
+        if tokens.iter().any(|&token| token < 0 || token as usize >= limit) {
+            error!("Token input {} holds values outside [0, {})", index, limit);
+            return Err(ErrNo::InvalidArgument);
+        }
+        if index == 1 && tokens.chunks_exact(seq_length).any(|row| row.iter().all(|&token| token == 0)) {
+            error!("Attention mask has a row without attended tokens");
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let tensor = Tensor::<B, 1, Int>::from_data(tokens, &self.device).reshape(dimens);
+        match index {
+            0 => self.input_ids = Some(tensor),
+            1 => self.attention_mask = Some(tensor),
+            _ => return Err(ErrNo::InvalidArgument),
+        }
+        Ok(())
+    }
+
+    /// Tokenizes newline-separated UTF-8 texts into a padded batch, setting ids and mask together.
+    pub fn set_text(&mut self, text: &[u8]) -> Result<(), ErrNo> {
+        let tokenizer = self.tokenizer.as_ref().ok_or(ErrNo::UnsupportedOperation)?;
+        let text = std::str::from_utf8(text).map_err(|_| ErrNo::InvalidEncoding)?;
+        let texts: Vec<&str> = text.lines().filter(|line| !line.trim().is_empty()).collect();
+        if texts.is_empty() {
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let batch = tokenizer.encode_batch(&texts, self.max_tokens)?;
+        let dimens = [batch.batch_size, batch.seq_length];
+        self.input_ids = Some(Tensor::<B, 1, Int>::from_data(batch.input_ids.as_slice(), &self.device).reshape(dimens));
+        self.attention_mask = Some(Tensor::<B, 1, Int>::from_data(batch.attention_mask.as_slice(), &self.device).reshape(dimens));
+        Ok(())
+    }
+
+    pub fn compute(&mut self, model: &BertModel<B>) -> Result<(), ErrNo> {
+        let input_ids = self.input_ids.clone().ok_or(ErrNo::InvalidArgument)?;
+
+        // without an explicit mask every token is attended
+        let attention_mask = match &self.attention_mask {
+            Some(mask) if mask.dims() == input_ids.dims() => mask.clone(),
+            Some(_) => return Err(ErrNo::InvalidArgument),
+            None => Tensor::ones(input_ids.dims(), &self.device),
+        };
+
+        self.output = Some(model.compute(input_ids, attention_mask));
+        Ok(())
+    }
+
+    pub fn get_output(&mut self) -> Vec<f32> {
+        self.output.as_ref().unwrap()
+            .clone().into_data().convert::<f32>().to_vec()
+            .expect("Failed to get output data")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::backend::NdArray;
+    use burn::tensor::{TensorData, Tolerance};
+    use super::*;
+
+    type TestBackend = NdArray<f32>;
+
+    fn pool(normalize: bool) -> TensorData {
+        let device = Default::default();
+        // two sequences of three 2-d states; the second pads its last token
+        let hidden = Tensor::<TestBackend, 1>::from_floats(
+            [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 2.0, 0.0, 4.0, 6.0, 100.0, 100.0],
+            &device,
+        ).reshape([2, 3, 2]);
+        let mask = Tensor::<TestBackend, 1, Int>::from_ints([1, 1, 1, 1, 1, 0], &device).reshape([2, 3]);
+        mean_pool(hidden, mask, normalize).into_data()
+    }
+
+    #[test]
+    fn mean_pool_averages_attended_tokens() {
+        let expected = TensorData::new(vec![3.0f32, 4.0, 3.0, 3.0], [2, 2]);
+        pool(false).assert_approx_eq::<f32>(&expected, Tolerance::rel_abs(1e-5, 1e-6));
+    }
+
+    #[test]
+    fn mean_pool_normalizes_to_unit_length() {
+        let half = 0.5f32.sqrt();
+        let expected = TensorData::new(vec![0.6f32, 0.8, half, half], [2, 2]);
+        pool(true).assert_approx_eq::<f32>(&expected, Tolerance::rel_abs(1e-5, 1e-6));
+    }
+
+    #[test]
+    fn mean_pool_of_an_unattended_row_is_zero() {
+        let device = Default::default();
+        let hidden = Tensor::<TestBackend, 3>::ones([1, 2, 2], &device);
+        let mask = Tensor::<TestBackend, 2, Int>::zeros([1, 2], &device);
+        let expected = TensorData::new(vec![0.0f32, 0.0], [1, 2]);
+        mean_pool(hidden, mask, false).into_data().assert_approx_eq::<f32>(&expected, Tolerance::rel_abs(1e-5, 1e-6));
+    }
+}
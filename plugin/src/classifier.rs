@@ -0,0 +1,396 @@
+use std::sync::OnceLock;
+use burn::backend::Autodiff;
+use burn::prelude::Backend;
+use burn::tensor::module::interpolate;
+use burn::tensor::ops::{InterpolateMode, InterpolateOptions};
+use burn::tensor::Int;
+use burn::Tensor;
+use log::{debug, error};
+use once_cell::sync::Lazy;
+use crate::config::{merge_config, ClassificationConfig, QuantizationConfig, Resolution, SaliencyConfig};
+use crate::ErrNo;
+use crate::mobilenet::MobileNetV2Model;
+use crate::quantization::quantize_classifier;
+use crate::resnet::ResnetModel;
+use crate::saliency::saliency_map;
+use crate::squeezenet::{SqueezeNet, SqueezenetModel};
+
+const INPUT_DIM: usize = 4;
+const OUTPUT_DIM: usize = 2;
+
+/// Input side all bundled classifiers were trained at.
+const PREFERRED_SIZE: usize = 224;
+
+/// Smallest side that survives the downsampling of every bundled classifier.
+pub(crate) const MIN_NATIVE_SIZE: usize = 32;
+
+/// ImageNet-1k class names, one per line in class index order.
+static IMAGENET_LABELS: Lazy<Vec<&'static str>> = Lazy::new(|| include_str!("imagenet_labels.txt").lines().collect());
+
+/// Output indices of a classifier context beyond the raw probabilities.
+const FEATURES_INDEX: i32 = 1;
+const TOP_K_INDEX: i32 = 2;
+const TOP_K_JSON_INDEX: i32 = 3;
+const SALIENCY_INDEX: i32 = 4;
+
+/// Image classifiers sharing one contract: NCHW `F32` input, `[N, classes]` class
+/// probabilities; 1000 ImageNet classes unless fine-tuned.
+#[derive(Clone)]
+pub enum ClassifierModel<B: Backend> {
+    Squeezenet(SqueezenetModel<B>),
+    Resnet(ResnetModel<B>),
+    MobileNetV2(MobileNetV2Model<B>),
+}
+
+/// A loaded classifier with the post-processing defaults and the device of its contexts.
+pub struct ClassifierGraph<B: Backend> {
+    pub model: ClassifierModel<B>,
+    config: ClassificationConfig,
+    device: B::Device,
+    /// Copy of the network on the autodiff backend, made for the first saliency map.
+    explainer: OnceLock<SqueezeNet<Autodiff<B>>>,
+}
+
+pub struct ClassifierContext<B: Backend> {
+    pub input: Option<Tensor<B, INPUT_DIM>>,
+    pub output: Option<Tensor<B, OUTPUT_DIM>>,
+    /// Pooled penultimate features, for models that expose them.
+    pub features: Option<Tensor<B, OUTPUT_DIM>>,
+    /// `[N, H, W]` saliency maps, when the context explains its scores.
+    pub saliency: Option<Tensor<B, 3>>,
+    config: ClassificationConfig,
+    max_batch_size: usize,
+    explainable: bool,
+    device: B::Device,
+}
+
+impl<B: Backend> ClassifierModel<B> {
+    /// Returns the class probabilities and, where the model exposes them, the
+    /// pooled features of the same forward pass.
+    pub fn compute(&self, input: Tensor<B, INPUT_DIM>) -> (Tensor<B, OUTPUT_DIM>, Option<Tensor<B, OUTPUT_DIM>>) {
+        match self {
+            ClassifierModel::Squeezenet(model) => {
+                let (features, probabilities) = model.compute(input);
+                (probabilities, Some(features))
+            }
+            ClassifierModel::Resnet(model) => (model.compute(input), None),
+            ClassifierModel::MobileNetV2(model) => (model.compute(input), None),
+        }
+    }
+
+    /// Whether saliency maps can be computed; the input gradients come from
+    /// an autodiff copy of the network, which only float SqueezeNet provides.
+    pub fn explainable(&self) -> bool {
+        matches!(self, ClassifierModel::Squeezenet(model) if model.network().is_some())
+    }
+}
+
+impl<B: Backend> ClassifierGraph<B> {
+    /// Fails with `UnsupportedOperation` if the config asks for saliency maps
+    /// from a model that cannot explain its scores.
+    pub fn new(model: ClassifierModel<B>, config: ClassificationConfig, device: &B::Device) -> Result<Self, ErrNo> {
+        if config.saliency.is_some() && !model.explainable() {
+            error!("Saliency maps are only supported for SqueezeNet");
+            return Err(ErrNo::UnsupportedOperation);
+        }
+        Ok(ClassifierGraph {
+            model,
+            config,
+            device: device.clone(),
+            explainer: OnceLock::new(),
+        })
+    }
+
+    /// Swaps the weights for int8 ones, calibrating on the encoded `samples`
+    /// if configured. An int8 network cannot explain its scores, so this fails
+    /// with `UnsupportedOperation` when the config asks for saliency maps.
+    pub fn quantize(mut self, config: &QuantizationConfig, samples: &[Vec<u8>]) -> Result<Self, ErrNo> {
+        if self.config.saliency.is_some() {
+            error!("Saliency maps are not supported for quantized models");
+            return Err(ErrNo::UnsupportedOperation);
+        }
+        self.model = quantize_classifier(self.model, config, samples, &self.device)?;
+        Ok(self)
+    }
+
+    pub fn config(&self) -> &ClassificationConfig {
+        &self.config
+    }
+
+    pub fn device(&self) -> &B::Device {
+        &self.device
+    }
+
+    fn explainer(&self) -> Result<&SqueezeNet<Autodiff<B>>, ErrNo> {
+        if let Some(explainer) = self.explainer.get() {
+            return Ok(explainer);
+        }
+        let network = match &self.model {
+            ClassifierModel::Squeezenet(model) => model.network().ok_or(ErrNo::UnsupportedOperation)?,
+            _ => return Err(ErrNo::UnsupportedOperation),
+        };
+        let explainer = network.to_autodiff(&self.device)?;
+        Ok(self.explainer.get_or_init(|| explainer))
+    }
+
+    pub fn init_execution_context(&self) -> ClassifierContext<B> {
+        ClassifierContext {
+            input: None,
+            output: None,
+            features: None,
+            saliency: None,
+            config: self.config.clone(),
+            max_batch_size: self.config.max_batch_size,
+            explainable: self.model.explainable(),
+            device: self.device.clone(),
+        }
+    }
+}
+
+impl<B: Backend> ClassifierContext<B> {
+    /// Overrides `top_k`, `resolution` or `saliency` with a partial JSON object.
+    pub fn configure(&mut self, json: &str) -> Result<(), ErrNo> {
+        let config: ClassificationConfig = merge_config(&self.config, json)?;
+        if config.saliency.is_some() && !self.explainable {
+            return Err(ErrNo::UnsupportedOperation);
+        }
+        self.config = config;
+        Ok(())
+    }
+    /// Accepts `[N, 3, H, W]` for `N` up to the graph's `max_batch_size`.
+    pub fn set_input(&mut self, input: &[f32], dimens: [usize; INPUT_DIM]) -> Result<(), ErrNo> {
+        let [batch_size, channels, height, width] = dimens;
+        if channels != 3 || batch_size == 0 || height == 0 || width == 0 {
+            return Err(ErrNo::InvalidArgument);
+        }
+        if batch_size > self.max_batch_size {
+            error!("Batch of {} exceeds the limit of {}", batch_size, self.max_batch_size);
+            return Err(ErrNo::TooLarge);
+        }
+
+        let tensor = Tensor::<B, 1>::from_data(input, &self.device).reshape(dimens);
+        self.input = Some(tensor);
+        Ok(())
+    }
+    /// Returns the input to run, resized on the device to the preferred size
+    /// unless the context runs at native resolution.
+    pub fn model_input(&self) -> Result<Tensor<B, INPUT_DIM>, ErrNo> {
+        let input = self.input.clone().ok_or(ErrNo::InvalidArgument)?;
+        let [_, _, height, width] = input.dims();
+
+        match self.config.resolution {
+            Resolution::Resize if height != PREFERRED_SIZE || width != PREFERRED_SIZE => {
+                debug!("Resizing {}x{} input to {}x{}", width, height, PREFERRED_SIZE, PREFERRED_SIZE);
+                Ok(interpolate(
+                    input,
+                    [PREFERRED_SIZE, PREFERRED_SIZE],
+                    InterpolateOptions::new(InterpolateMode::Bilinear),
+                ))
+            }
+            Resolution::Native if height < MIN_NATIVE_SIZE || width < MIN_NATIVE_SIZE => Err(ErrNo::InvalidArgument),
+            _ => Ok(input),
+        }
+    }
+    pub fn compute(&mut self, graph: &ClassifierGraph<B>) -> Result<(), ErrNo> {
+        let input = self.model_input()?;
+        let (output, features) = graph.model.compute(input.clone());
+        self.saliency = match &self.config.saliency {
+            Some(config) => Some(self.saliency(graph, input, &output, config)?),
+            None => None,
+        };
+        self.output = Some(output);
+        self.features = features;
+        Ok(())
+    }
+    /// Explains the configured class, or each image's top class, with a
+    /// saliency map at the size of the context's input.
+    fn saliency(
+        &self,
+        graph: &ClassifierGraph<B>,
+        input: Tensor<B, INPUT_DIM>,
+        probabilities: &Tensor<B, OUTPUT_DIM>,
+        config: &SaliencyConfig,
+    ) -> Result<Tensor<B, 3>, ErrNo> {
+        let [batch_size, classes] = probabilities.dims();
+        let targets = match config.class {
+            Some(class) if class >= classes => return Err(ErrNo::InvalidArgument),
+            Some(class) => Tensor::<B, OUTPUT_DIM, Int>::full([batch_size, 1], class as i64, &self.device),
+            None => probabilities.clone().argmax(1),
+        };
+        let map = saliency_map(graph.explainer()?, input, targets, config);
+
+        // the map follows the resized model input; scale it back to the guest's input
+        let [_, _, height, width] = self.input.as_ref().ok_or(ErrNo::InvalidArgument)?.dims();
+        let [_, map_height, map_width] = map.dims();
+        if map_height == height && map_width == width {
+            return Ok(map);
+        }
+        let map = interpolate(
+            map.unsqueeze_dim::<INPUT_DIM>(1),
+            [height, width],
+            InterpolateOptions::new(InterpolateMode::Bilinear),
+        );
+        Ok(map.reshape([batch_size, height, width]))
+    }
+    pub fn get_output(&mut self) -> Vec<f32> {
+        self.output.as_ref().unwrap()
+            .clone().into_data().convert::<f32>().to_vec()
+            .expect("Failed to get output data")
+    }
+    pub fn get_features(&mut self) -> Vec<f32> {
+        self.features.as_ref().unwrap()
+            .clone().into_data().convert::<f32>().to_vec()
+            .expect("Failed to get feature data")
+    }
+    /// Selects the `top_k` best classes per image on the device and returns
+    /// their indices and probabilities, row by row.
+    fn top_k(&self) -> Result<(usize, Vec<i64>, Vec<f32>), ErrNo> {
+        let output = self.output.clone().ok_or(ErrNo::RuntimeError)?;
+        let [_, classes] = output.dims();
+        let k = self.config.top_k.clamp(1, classes);
+
+        let (scores, indices) = output.topk_with_indices(k, 1);
+        let scores = scores.into_data().convert::<f32>().to_vec::<f32>().map_err(|_| ErrNo::RuntimeError)?;
+        let indices = indices.into_data().convert::<i64>().to_vec::<i64>().map_err(|_| ErrNo::RuntimeError)?;
+        Ok((k, indices, scores))
+    }
+    fn label(&self, index: usize) -> &str {
+        match &self.config.labels {
+            Some(labels) => labels.get(index).map(String::as_str),
+            None => IMAGENET_LABELS.get(index).copied(),
+        }.unwrap_or("")
+    }
+    /// Returns the requested post-processed output: 1 holds the pooled features,
+    /// 2 the top-k as `[N, k, 2]` `f32` (class index, probability) pairs and 3 the
+    /// same as JSON, with the configured or ImageNet labels, and 4 the `[N, H, W]`
+    /// saliency maps, one `[H, W]` map per image.
+    pub fn get_post_processed(&mut self, index: i32) -> Result<Vec<u8>, ErrNo> {
+        match index {
+            FEATURES_INDEX => {
+                if self.features.is_none() {
+                    return Err(ErrNo::UnsupportedOperation);
+                }
+                Ok(bytemuck::cast_slice(&self.get_features()).to_vec())
+            }
+            TOP_K_INDEX => {
+                let (_, indices, scores) = self.top_k()?;
+                let packed: Vec<f32> = indices
+                    .iter()
+                    .zip(&scores)
+                    .flat_map(|(&index, &score)| [index as f32, score])
+                    .collect();
+                Ok(bytemuck::cast_slice(&packed).to_vec())
+            }
+            TOP_K_JSON_INDEX => {
+                let (k, indices, scores) = self.top_k()?;
+                let rows: Vec<Vec<serde_json::Value>> = indices
+                    .chunks(k)
+                    .zip(scores.chunks(k))
+                    .map(|(indices, scores)| {
+                        indices
+                            .iter()
+                            .zip(scores)
+                            .map(|(&index, &score)| serde_json::json!({
+                                "index": index,
+                                "label": self.label(index as usize),
+                                "score": score,
+                            }))
+                            .collect()
+                    })
+                    .collect();
+                serde_json::to_vec(&rows).map_err(|_| ErrNo::RuntimeError)
+            }
+            SALIENCY_INDEX => {
+                let saliency = self.saliency.clone().ok_or(ErrNo::UnsupportedOperation)?;
+                let saliency = saliency.into_data().convert::<f32>().to_vec::<f32>().map_err(|_| ErrNo::RuntimeError)?;
+                Ok(bytemuck::cast_slice(&saliency).to_vec())
+            }
+            _ => Err(ErrNo::UnsupportedOperation),
+        }
+    }
+    /// Shape of the output at `index`; the JSON output is a flat byte string.
+    pub fn output_shape(&mut self, index: i32) -> Result<Vec<usize>, ErrNo> {
+        let output = self.output.as_ref().ok_or(ErrNo::RuntimeError)?;
+        let [batch_size, classes] = output.dims();
+        match index {
+            0 => Ok(vec![batch_size, classes]),
+            FEATURES_INDEX => self.features
+                .as_ref()
+                .map(|features| features.dims().to_vec())
+                .ok_or(ErrNo::UnsupportedOperation),
+            TOP_K_INDEX => Ok(vec![batch_size, self.config.top_k.clamp(1, classes), 2]),
+            TOP_K_JSON_INDEX => Ok(vec![self.get_post_processed(index)?.len()]),
+            SALIENCY_INDEX => self.saliency
+                .as_ref()
+                .map(|saliency| saliency.dims().to_vec())
+                .ok_or(ErrNo::UnsupportedOperation),
+            _ => Err(ErrNo::UnsupportedOperation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::backend::NdArray;
+    use burn::tensor::Distribution;
+    use super::*;
+
+    type TestBackend = NdArray<f32>;
+
+    const SIZE: usize = PREFERRED_SIZE;
+
+    fn squeezenet(config: ClassificationConfig) -> ClassifierGraph<TestBackend> {
+        let device = Default::default();
+        ClassifierGraph::new(ClassifierModel::Squeezenet(SqueezenetModel::new(true, &device)), config, &device).unwrap()
+    }
+
+    fn random_input(batch_size: usize, device: &<TestBackend as Backend>::Device) -> Vec<f32> {
+        Tensor::<TestBackend, 1>::random([batch_size * 3 * SIZE * SIZE], Distribution::Normal(0.0, 1.0), device)
+            .into_data()
+            .to_vec()
+            .unwrap()
+    }
+
+    fn run(graph: &ClassifierGraph<TestBackend>, input: &[f32], batch_size: usize) -> ClassifierContext<TestBackend> {
+        let mut context = graph.init_execution_context();
+        context.set_input(input, [batch_size, 3, SIZE, SIZE]).unwrap();
+        context.compute(graph).unwrap();
+        context
+    }
+
+    #[test]
+    fn batched_rows_match_single_runs() {
+        const BATCH_SIZE: usize = 3;
+        let graph = squeezenet(ClassificationConfig::default());
+        let input = random_input(BATCH_SIZE, graph.device());
+
+        let mut batched = run(&graph, &input, BATCH_SIZE);
+        assert_eq!(batched.output_shape(0).unwrap(), vec![BATCH_SIZE, 1000]);
+        assert_eq!(batched.output_shape(FEATURES_INDEX).unwrap(), vec![BATCH_SIZE, 512]);
+        let probabilities = batched.get_output();
+        let features = batched.get_features();
+
+        let items = input.chunks(input.len() / BATCH_SIZE);
+        for (i, item) in items.enumerate() {
+            let mut single = run(&graph, item, 1);
+            let rows = [
+                (&probabilities[i * 1000..(i + 1) * 1000], single.get_output()),
+                (&features[i * 512..(i + 1) * 512], single.get_features()),
+            ];
+            for (row, expected) in rows {
+                let error = row.iter().zip(&expected).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+                assert!(error < 1e-5, "item {} differs from its single run by {}", i, error);
+            }
+        }
+    }
+
+    #[test]
+    fn batches_over_the_limit_are_rejected() {
+        let graph = squeezenet(ClassificationConfig { max_batch_size: 2, ..Default::default() });
+        let input = random_input(3, graph.device());
+
+        let mut context = graph.init_execution_context();
+        assert_eq!(context.set_input(&input, [3, 3, SIZE, SIZE]), Err(ErrNo::TooLarge));
+        assert_eq!(context.set_input(&input[..2 * 3 * SIZE * SIZE], [2, 3, SIZE, SIZE]), Ok(()));
+    }
+}
@@ -0,0 +1,395 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use burn::module::{Module, Param};
+use burn::nn::attention::{generate_autoregressive_mask, MhaInput, MultiHeadAttention, MultiHeadAttentionConfig};
+use burn::nn::conv::{Conv2d, Conv2dConfig};
+use burn::nn::{Embedding, EmbeddingConfig, LayerNorm, LayerNormConfig, Linear, LinearConfig};
+use burn::prelude::Backend;
+use burn::tensor::activation::sigmoid;
+use burn::tensor::{Int, Tensor};
+use burn_import::safetensors::AdapterType;
+use log::{error, info};
+use serde::Deserialize;
+use crate::config::{merge_config, ClipContextConfig, ClipMode};
+use crate::ErrNo;
+use crate::tokenizer::TextTokenizer;
+use crate::weights::load_safetensors;
+
+/// Key remaps from HuggingFace CLIP checkpoints to `Clip` field names.
+const CLIP_REMAPS: [(&str, &str); 6] = [
+    ("(text_model|vision_model)\\.embeddings\\.", "$1."),
+    ("encoder\\.layers", "layers"),
+    ("self_attn\\.q_proj", "self_attn.query"),
+    ("self_attn\\.k_proj", "self_attn.key"),
+    ("self_attn\\.v_proj", "self_attn.value"),
+    ("self_attn\\.out_proj", "self_attn.output"),
+];
+
+/// Transformer sizes of one CLIP tower; text-only and vision-only fields default to 0.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClipTowerConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_attention_heads: usize,
+    pub num_hidden_layers: usize,
+    #[serde(default = "default_layer_norm_eps")]
+    pub layer_norm_eps: f64,
+    #[serde(default)]
+    pub vocab_size: usize,
+    #[serde(default)]
+    pub max_position_embeddings: usize,
+    #[serde(default)]
+    pub image_size: usize,
+    #[serde(default)]
+    pub patch_size: usize,
+}
+
+/// Subset of a HuggingFace CLIP `config.json`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClipConfig {
+    pub text_config: ClipTowerConfig,
+    pub vision_config: ClipTowerConfig,
+    pub projection_dim: usize,
+}
+
+fn default_layer_norm_eps() -> f64 {
+    1e-5
+}
+
+#[derive(Module, Debug)]
+pub struct ClipMlp<B: Backend> {
+    fc1: Linear<B>,
+    fc2: Linear<B>,
+}
+
+#[derive(Module, Debug)]
+pub struct ClipEncoderLayer<B: Backend> {
+    layer_norm1: LayerNorm<B>,
+    self_attn: MultiHeadAttention<B>,
+    layer_norm2: LayerNorm<B>,
+    mlp: ClipMlp<B>,
+}
+
+#[derive(Module, Debug)]
+pub struct ClipTextModel<B: Backend> {
+    token_embedding: Embedding<B>,
+    position_embedding: Embedding<B>,
+    layers: Vec<ClipEncoderLayer<B>>,
+    final_layer_norm: LayerNorm<B>,
+}
+
+#[derive(Module, Debug)]
+pub struct ClipVisionModel<B: Backend> {
+    class_embedding: Param<Tensor<B, 1>>,
+    patch_embedding: Conv2d<B>,
+    position_embedding: Embedding<B>,
+    pre_layrnorm: LayerNorm<B>,
+    layers: Vec<ClipEncoderLayer<B>>,
+    post_layernorm: LayerNorm<B>,
+}
+
+#[derive(Module, Debug)]
+pub struct Clip<B: Backend> {
+    text_model: ClipTextModel<B>,
+    vision_model: ClipVisionModel<B>,
+    text_projection: Linear<B>,
+    visual_projection: Linear<B>,
+}
+
+impl ClipTowerConfig {
+    fn init_layers<B: Backend>(&self, device: &B::Device) -> Vec<ClipEncoderLayer<B>> {
+        (0..self.num_hidden_layers)
+            .map(|_| ClipEncoderLayer {
+                layer_norm1: self.init_layer_norm(device),
+                self_attn: MultiHeadAttentionConfig::new(self.hidden_size, self.num_attention_heads)
+                    .with_dropout(0.0)
+                    .init(device),
+                layer_norm2: self.init_layer_norm(device),
+                mlp: ClipMlp {
+                    fc1: LinearConfig::new(self.hidden_size, self.intermediate_size).init(device),
+                    fc2: LinearConfig::new(self.intermediate_size, self.hidden_size).init(device),
+                },
+            })
+            .collect()
+    }
+
+    fn init_layer_norm<B: Backend>(&self, device: &B::Device) -> LayerNorm<B> {
+        LayerNormConfig::new(self.hidden_size).with_epsilon(self.layer_norm_eps).init(device)
+    }
+}
+
+impl ClipConfig {
+    pub fn init<B: Backend>(&self, device: &B::Device) -> Clip<B> {
+        let text = &self.text_config;
+        let vision = &self.vision_config;
+        let num_patches = (vision.image_size / vision.patch_size).pow(2);
+
+        Clip {
+            text_model: ClipTextModel {
+                token_embedding: EmbeddingConfig::new(text.vocab_size, text.hidden_size).init(device),
+                position_embedding: EmbeddingConfig::new(text.max_position_embeddings, text.hidden_size).init(device),
+                layers: text.init_layers(device),
+                final_layer_norm: text.init_layer_norm(device),
+            },
+            vision_model: ClipVisionModel {
+                class_embedding: Param::from_tensor(Tensor::zeros([vision.hidden_size], device)),
+                patch_embedding: Conv2dConfig::new([3, vision.hidden_size], [vision.patch_size, vision.patch_size])
+                    .with_stride([vision.patch_size, vision.patch_size])
+                    .with_bias(false)
+                    .init(device),
+                position_embedding: EmbeddingConfig::new(num_patches + 1, vision.hidden_size).init(device),
+                pre_layrnorm: vision.init_layer_norm(device),
+                layers: vision.init_layers(device),
+                post_layernorm: vision.init_layer_norm(device),
+            },
+            text_projection: LinearConfig::new(text.hidden_size, self.projection_dim)
+                .with_bias(false)
+                .init(device),
+            visual_projection: LinearConfig::new(vision.hidden_size, self.projection_dim)
+                .with_bias(false)
+                .init(device),
+        }
+    }
+}
+
+/// The activation CLIP was trained with: `x * sigmoid(1.702 * x)`.
+fn quick_gelu<B: Backend>(x: Tensor<B, 3>) -> Tensor<B, 3> {
+    x.clone() * sigmoid(x * 1.702)
+}
+
+impl<B: Backend> ClipEncoderLayer<B> {
+    /// Pre-norm transformer layer; `causal` hides later positions (text tower).
+    pub fn forward(&self, x: Tensor<B, 3>, causal: bool) -> Tensor<B, 3> {
+        let mut input = MhaInput::self_attn(self.layer_norm1.forward(x.clone()));
+        if causal {
+            let [batch_size, seq_length, _] = x.dims();
+            input = input.mask_attn(generate_autoregressive_mask(batch_size, seq_length, &x.device()));
+        }
+        let x = x + self.self_attn.forward(input).context;
+
+        let hidden = quick_gelu(self.mlp.fc1.forward(self.layer_norm2.forward(x.clone())));
+        x + self.mlp.fc2.forward(hidden)
+    }
+}
+
+/// Scales every row to unit length.
+fn l2_normalize<B: Backend>(x: Tensor<B, 2>) -> Tensor<B, 2> {
+    let norm = x.clone().powf_scalar(2.0).sum_dim(1).sqrt().clamp_min(1e-12);
+    x / norm
+}
+
+impl<B: Backend> Clip<B> {
+    /// Embeds `[batch, seq]` token ids; the sequence is pooled at its end-of-text
+    /// token, which has the highest id in CLIP's vocabulary.
+    pub fn embed_text(&self, input_ids: Tensor<B, 2, Int>) -> Tensor<B, 2> {
+        let model = &self.text_model;
+        let [batch_size, seq_length] = input_ids.dims();
+        let device = input_ids.device();
+
+        let positions = Tensor::<B, 1, Int>::arange(0..seq_length as i64, &device)
+            .unsqueeze::<2>()
+            .repeat_dim(0, batch_size);
+        let eos_positions = input_ids.clone().argmax(1);
+
+        let mut hidden = model.token_embedding.forward(input_ids) + model.position_embedding.forward(positions);
+        for layer in &model.layers {
+            hidden = layer.forward(hidden, true);
+        }
+        let hidden = model.final_layer_norm.forward(hidden);
+
+        let [_, _, hidden_size] = hidden.dims();
+        let pooled = hidden
+            .gather(1, eos_positions.unsqueeze_dim::<3>(2).expand([batch_size, 1, hidden_size]))
+            .reshape([batch_size, hidden_size]);
+
+        l2_normalize(self.text_projection.forward(pooled))
+    }
+
+    /// Embeds `[batch, 3, H, W]` images normalized with CLIP's mean and std.
+    pub fn embed_image(&self, images: Tensor<B, 4>) -> Tensor<B, 2> {
+        let model = &self.vision_model;
+        let [batch_size, _, _, _] = images.dims();
+        let device = images.device();
+
+        // [batch, hidden, grid, grid] -> [batch, patches, hidden]
+        let patches = model.patch_embedding.forward(images).flatten::<3>(2, 3).swap_dims(1, 2);
+        let [_, num_patches, hidden_size] = patches.dims();
+
+        let class_embedding = model.class_embedding.val()
+            .reshape([1, 1, hidden_size])
+            .expand([batch_size, 1, hidden_size]);
+        let positions = Tensor::<B, 1, Int>::arange(0..(num_patches + 1) as i64, &device)
+            .unsqueeze::<2>()
+            .repeat_dim(0, batch_size);
+
+        let embeddings = Tensor::cat(vec![class_embedding, patches], 1) + model.position_embedding.forward(positions);
+        let mut hidden = model.pre_layrnorm.forward(embeddings);
+        for layer in &model.layers {
+            hidden = layer.forward(hidden, false);
+        }
+
+        let pooled = hidden
+            .slice([0..batch_size, 0..1, 0..hidden_size])
+            .reshape([batch_size, hidden_size]);
+        let pooled = model.post_layernorm.forward(pooled);
+
+        l2_normalize(self.visual_projection.forward(pooled))
+    }
+}
+
+pub struct ClipModel<B: Backend> {
+    model: Clip<B>,
+    config: ClipContextConfig,
+    max_tokens: usize,
+    vocab_size: usize,
+    image_size: usize,
+    tokenizer: Option<Arc<TextTokenizer>>,
+    device: B::Device,
+}
+
+pub struct ClipContext<B: Backend> {
+    pub images: Option<Tensor<B, 4>>,
+    pub input_ids: Option<Tensor<B, 2, Int>>,
+    pub output: Option<Tensor<B, 2>>,
+    config: ClipContextConfig,
+    max_tokens: usize,
+    vocab_size: usize,
+    image_size: usize,
+    tokenizer: Option<Arc<TextTokenizer>>,
+    device: B::Device,
+}
+
+impl<B: Backend> ClipModel<B> {
+    /// Loads a HuggingFace CLIP directory holding `config.json`,
+    /// `model.safetensors` and optionally `tokenizer.json`.
+    pub fn new(path: &Path, config: ClipContextConfig, device: &B::Device) -> Result<Self, ErrNo> {
+        let context_config = config;
+        let config: ClipConfig = fs::read_to_string(path.join("config.json"))
+            .map_err(|err| err.to_string())
+            .and_then(|json| serde_json::from_str(&json).map_err(|err| err.to_string()))
+            .map_err(|err| {
+                error!("Failed to read CLIP config in {}: {}", path.display(), err);
+                ErrNo::NotFound
+            })?;
+
+        let record = load_safetensors::<B, _>(
+            &path.join("model.safetensors"),
+            &CLIP_REMAPS,
+            AdapterType::PyTorch,
+            device,
+        ).map_err(|err| {
+            error!("Failed to load CLIP weights in {}: {:?}", path.display(), err);
+            ErrNo::RuntimeError
+        })?;
+
+        info!(
+            "Loaded CLIP: {}px images, {}-dim embeddings",
+            config.vision_config.image_size, config.projection_dim
+        );
+
+        let model = config.init::<B>(device).load_record(record);
+        let tokenizer = TextTokenizer::from_dir(path).map(Arc::new);
+
+        Ok(ClipModel {
+            model,
+            config: context_config,
+            max_tokens: config.text_config.max_position_embeddings,
+            vocab_size: config.text_config.vocab_size,
+            image_size: config.vision_config.image_size,
+            tokenizer,
+            device: device.clone(),
+        })
+    }
+
+    pub fn tokenizer(&self) -> Option<&TextTokenizer> {
+        self.tokenizer.as_deref()
+    }
+
+    pub fn init_execution_context(&self) -> ClipContext<B> {
+        ClipContext {
+            images: None,
+            input_ids: None,
+            output: None,
+            config: self.config.clone(),
+            max_tokens: self.max_tokens,
+            vocab_size: self.vocab_size,
+            image_size: self.image_size,
+            tokenizer: self.tokenizer.clone(),
+            device: self.device.clone(),
+        }
+    }
+}
+
+impl<B: Backend> ClipContext<B> {
+    /// Switches between image and text mode with a partial JSON object.
+    pub fn configure(&mut self, json: &str) -> Result<(), ErrNo> {
+        self.config = merge_config(&self.config, json)?;
+        self.output = None;
+        Ok(())
+    }
+
+    /// Takes `[N, 3, image_size, image_size]`; the position embeddings fix the size.
+    pub fn set_images(&mut self, input: &[f32], dimens: [usize; 4]) -> Result<(), ErrNo> {
+        if self.config.mode != ClipMode::Image {
+            return Err(ErrNo::InvalidArgument);
+        }
+        let [batch_size, channels, height, width] = dimens;
+        if batch_size == 0 || channels != 3 || height != self.image_size || width != self.image_size {
+            error!("CLIP expects [N, 3, {size}, {size}] images, got {:?}", dimens, size = self.image_size);
+            return Err(ErrNo::InvalidArgument);
+        }
+        self.images = Some(Tensor::<B, 1>::from_data(input, &self.device).reshape(dimens));
+        Ok(())
+    }
+
+    /// Takes `[N, seq]` token ids, `seq` bounded by the position embeddings.
+    pub fn set_tokens(&mut self, tokens: &[i64], dimens: [usize; 2]) -> Result<(), ErrNo> {
+        if self.config.mode != ClipMode::Text {
+            return Err(ErrNo::InvalidArgument);
+        }
+        let [batch_size, seq_length] = dimens;
+        if batch_size == 0 || seq_length == 0 || seq_length > self.max_tokens {
+            error!("CLIP takes 1 to {} tokens per text, got {:?}", self.max_tokens, dimens);
+            return Err(ErrNo::InvalidArgument);
+        }
+        if let Some(token) = tokens.iter().find(|&&token| token < 0 || token as usize >= self.vocab_size) {
+            error!("Token {} is outside the vocabulary of {}", token, self.vocab_size);
+            return Err(ErrNo::InvalidArgument);
+        }
+        self.input_ids = Some(Tensor::<B, 1, Int>::from_data(tokens, &self.device).reshape(dimens));
+        Ok(())
+    }
+
+    /// Tokenizes newline-separated UTF-8 texts into a padded batch.
+    pub fn set_text(&mut self, text: &[u8]) -> Result<(), ErrNo> {
+        let tokenizer = self.tokenizer.as_ref().ok_or(ErrNo::UnsupportedOperation)?;
+        let text = std::str::from_utf8(text).map_err(|_| ErrNo::InvalidEncoding)?;
+        let texts: Vec<&str> = text.lines().filter(|line| !line.trim().is_empty()).collect();
+        if texts.is_empty() {
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let batch = tokenizer.encode_batch(&texts, self.max_tokens)?;
+        self.set_tokens(&batch.input_ids, [batch.batch_size, batch.seq_length])
+    }
+
+    pub fn compute(&mut self, model: &ClipModel<B>) -> Result<(), ErrNo> {
+        let output = match self.config.mode {
+            ClipMode::Image => model.model.embed_image(self.images.clone().ok_or(ErrNo::InvalidArgument)?),
+            ClipMode::Text => model.model.embed_text(self.input_ids.clone().ok_or(ErrNo::InvalidArgument)?),
+        };
+        self.output = Some(output);
+        Ok(())
+    }
+
+    /// Output 0 holds the L2-normalized `[batch, projection_dim]` embeddings.
+    pub fn get_output(&self, index: i32) -> Result<Vec<u8>, ErrNo> {
+        if index != 0 {
+            return Err(ErrNo::InvalidArgument);
+        }
+        let output = self.output.clone().ok_or(ErrNo::RuntimeError)?;
+        let output = output.into_data().convert::<f32>().to_vec::<f32>().map_err(|_| ErrNo::RuntimeError)?;
+        Ok(bytemuck::cast_slice(&output).to_vec())
+    }
+}
@@ -0,0 +1,591 @@
+use std::path::PathBuf;
+use cubecl_runtime::config::autotune::AutotuneLevel;
+use log::error;
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use crate::ErrNo;
+
+/// Process-wide settings, read once from the environment.
+pub static PLUGIN_CONFIG: Lazy<PluginConfig> = Lazy::new(PluginConfig::from_env);
+
+/// Model architectures that can back a graph. In JSON configs `kind` takes
+/// the same names as `load_by_name`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModelKind {
+    Squeezenet,
+    Resnet18,
+    Resnet50,
+    Mobilenetv2,
+    Bert,
+    Gpt2,
+    YoloxNano,
+    YoloxTiny,
+    YoloxS,
+    Unet,
+    Clip,
+    Crnn,
+    Recurrent,
+}
+
+impl ModelKind {
+    /// Resolves a model kind from the name a guest passes to `load_by_name`
+    /// or writes into the load bytes. Matching ignores case and surrounding whitespace.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "squeezenet" | "squeezenet1" | "squeezenet1.1" => Some(ModelKind::Squeezenet),
+            "resnet18" | "resnet-18" => Some(ModelKind::Resnet18),
+            "resnet50" | "resnet-50" => Some(ModelKind::Resnet50),
+            "mobilenetv2" | "mobilenet-v2" | "mobilenet_v2" => Some(ModelKind::Mobilenetv2),
+            "bert" | "minilm" | "all-minilm-l6-v2" => Some(ModelKind::Bert),
+            "gpt2" | "gpt-2" => Some(ModelKind::Gpt2),
+            "yolox-nano" | "yolox_nano" => Some(ModelKind::YoloxNano),
+            "yolox-tiny" | "yolox_tiny" => Some(ModelKind::YoloxTiny),
+            "yolox-s" | "yolox_s" | "yolox" => Some(ModelKind::YoloxS),
+            "unet" => Some(ModelKind::Unet),
+            "clip" | "clip-vit-b-32" | "clip-vit-base-patch32" => Some(ModelKind::Clip),
+            "crnn" | "ocr" => Some(ModelKind::Crnn),
+            "recurrent" | "lstm" | "gru" => Some(ModelKind::Recurrent),
+            _ => None,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ModelKind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        ModelKind::from_name(&name).ok_or_else(|| de::Error::custom(format!("unknown model kind `{}`", name)))
+    }
+}
+
+/// Execution target of a graph, mirroring wasi-nn's `ExecutionTarget`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    Cpu,
+    Gpu,
+}
+
+impl Target {
+    pub fn from_id(id: i32) -> Option<Self> {
+        match id {
+            0 => Some(Target::Cpu),
+            1 => Some(Target::Gpu),
+            _ => None,
+        }
+    }
+}
+
+/// wgpu adapter of a GPU-target graph, e.g. `"cpu"`, `{"discrete-gpu": 1}` or `{"name": "RTX"}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GpuDevice {
+    /// The adapter wgpu picks by default.
+    #[default]
+    Default,
+    /// The n-th discrete GPU.
+    DiscreteGpu(usize),
+    /// The n-th integrated GPU.
+    IntegratedGpu(usize),
+    /// A software adapter.
+    Cpu,
+    /// The first adapter whose name contains this string, ignoring case.
+    Name(String),
+}
+
+/// Float element type of a graph. Half precision runs on Wgpu, f64 on NdArray.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+    F64,
+}
+
+/// Backend that runs CPU-target graphs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CpuBackend {
+    /// The portable `ndarray` backend.
+    #[default]
+    Ndarray,
+    /// Burn's JIT-compiled CubeCL CPU backend.
+    Cubecl,
+}
+
+/// Policy for GPU graphs on hosts without a usable wgpu adapter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GpuFallback {
+    /// Build the graph on the configured CPU backend instead.
+    #[default]
+    Cpu,
+    /// Fail the load with `UnsupportedOperation`.
+    Strict,
+}
+
+/// Kernel autotuning of Wgpu graphs, set with `WASI_NN_BURN_AUTOTUNE_LEVEL`.
+#[derive(Debug, Clone)]
+pub enum Autotune {
+    /// No tuning; kernels use their default variants. CubeCL has no runtime
+    /// switch for this, so only builds without the `autotune` feature honor it.
+    Off,
+    /// Tune with the given effort; `minimal` tries the fewest variants.
+    Level(AutotuneLevel),
+}
+
+/// Settings shared by every graph of the process.
+#[derive(Debug, Clone, Default)]
+pub struct PluginConfig {
+    /// Directory the Wgpu autotune results are written to and reused from, so
+    /// short-lived processes skip the tuning a previous one already did.
+    /// Set with `WASI_NN_BURN_TUNE_CACHE`.
+    pub tune_cache_dir: Option<PathBuf>,
+    /// Whether and how hard Wgpu kernels are autotuned: `off`, or a level of
+    /// `minimal`, `balanced`, `extensive` or `full` (`0` to `3`).
+    /// Set with `WASI_NN_BURN_AUTOTUNE_LEVEL`.
+    pub autotune: Option<Autotune>,
+    /// Warm-up passes of loads whose config does not set them.
+    /// Set with `WASI_NN_BURN_WARMUP`.
+    pub warmup_passes: usize,
+}
+
+impl PluginConfig {
+    pub fn from_env() -> Self {
+        PluginConfig {
+            tune_cache_dir: std::env::var_os("WASI_NN_BURN_TUNE_CACHE")
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from),
+            autotune: std::env::var("WASI_NN_BURN_AUTOTUNE_LEVEL")
+                .ok()
+                .and_then(|level| match level.trim().to_ascii_lowercase().as_str() {
+                    "off" | "none" => Some(Autotune::Off),
+                    "minimal" | "0" => Some(Autotune::Level(AutotuneLevel::Minimal)),
+                    "balanced" | "1" => Some(Autotune::Level(AutotuneLevel::Balanced)),
+                    "extensive" | "2" => Some(Autotune::Level(AutotuneLevel::Extensive)),
+                    "full" | "3" => Some(Autotune::Level(AutotuneLevel::Full)),
+                    _ => {
+                        error!("Unknown autotune level `{}`", level);
+                        None
+                    }
+                }),
+            warmup_passes: std::env::var("WASI_NN_BURN_WARMUP")
+                .ok()
+                .and_then(|passes| passes.trim().parse().ok())
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Load-time configuration, read as JSON from the first graph builder
+/// or from the config passed to `load_by_name_with_config`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphConfig {
+    pub kind: Option<ModelKind>,
+    pub target: Option<Target>,
+    /// Adapter of graphs on the GPU target.
+    pub device: GpuDevice,
+    /// Backend of graphs on the CPU target.
+    pub cpu_backend: CpuBackend,
+    /// Float element type of the graph's weights and activations.
+    pub precision: Precision,
+    /// What to do when the GPU target is requested on a host without a wgpu adapter.
+    pub gpu_fallback: GpuFallback,
+    /// Host path of the model files, for models without embedded weights: a directory
+    /// for HuggingFace models, a burn record file for our own architectures.
+    pub path: Option<String>,
+    /// L2-normalize embedding outputs, including SqueezeNet features; defaults to true.
+    pub normalize: Option<bool>,
+    /// Default sampling settings of text-generation contexts.
+    pub generation: GenerationConfig,
+    /// Default top-k post-processing of classifier contexts.
+    pub classification: ClassificationConfig,
+    /// Default thresholds of detection contexts.
+    pub detection: DetectionConfig,
+    /// Architecture of segmentation models.
+    pub segmentation: SegmentationConfig,
+    /// Default mode of CLIP contexts.
+    pub clip: ClipContextConfig,
+    /// Architecture and default decoding of OCR models.
+    pub ocr: OcrConfig,
+    /// Architecture of recurrent time-series models.
+    pub recurrent: RecurrentConfig,
+    /// Dummy forward passes run right after the load.
+    pub warmup: WarmupConfig,
+    /// int8 post-training quantization of classifier weights.
+    pub quantization: Option<QuantizationConfig>,
+}
+
+/// Scale granularity of symmetric int8 weight quantization.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuantGranularity {
+    /// One scale per weight tensor.
+    #[default]
+    Tensor,
+    /// One scale per output channel of every convolution weight. SqueezeNet only.
+    Channel,
+}
+
+/// Post-training quantization applied when a classifier is loaded. Weights are
+/// stored as int8 with scales from their own min-max range; activations stay in
+/// floating point unless `calibrate` is set.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuantizationConfig {
+    pub granularity: QuantGranularity,
+    /// Also quantize the input of every convolution to int8, with one scale per
+    /// layer from the min-max range it takes on the calibration samples: encoded
+    /// images passed as the load builders after the config. SqueezeNet only.
+    pub calibrate: bool,
+}
+
+/// Forward passes on dummy input that compile shaders and allocate buffers
+/// before the first guest `compute`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WarmupConfig {
+    /// Number of passes; unset falls back to the process-wide setting.
+    pub passes: Option<usize>,
+    /// Input shape of the passes, e.g. `[8, 3, 224, 224]` to warm up batched
+    /// inference; defaults to a single input of the model's usual size.
+    pub shape: Option<Vec<usize>>,
+}
+
+/// Sampling settings of a text-generation context.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationConfig {
+    /// Softmax temperature; `0` selects the most likely token (greedy decoding).
+    pub temperature: f32,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f32>,
+    pub max_tokens: usize,
+    /// Fixed sampling seed for reproducible output.
+    pub seed: Option<u64>,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        GenerationConfig {
+            temperature: 1.0,
+            top_k: None,
+            top_p: None,
+            max_tokens: 128,
+            seed: None,
+        }
+    }
+}
+
+impl GraphConfig {
+    /// Interprets raw load bytes. Accepts either a JSON object or a bare
+    /// model name; other bytes yield `None` so the caller can fall back to
+    /// its default model. A JSON object that does not parse is an error.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Option<Self>, ErrNo> {
+        let text = match std::str::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(_) => return Ok(None),
+        };
+
+        if text.trim_start().starts_with('{') {
+            return serde_json::from_str::<GraphConfig>(text).map(Some).map_err(|err| {
+                error!("Invalid load config: {}", err);
+                ErrNo::InvalidArgument
+            });
+        }
+
+        Ok(ModelKind::from_name(text).map(|kind| GraphConfig { kind: Some(kind), ..Default::default() }))
+    }
+}
+
+/// Post-processing of an image-classification context.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClassificationConfig {
+    /// Number of best classes in the top-k outputs.
+    pub top_k: usize,
+    /// Largest batch `N` a context accepts; fixed when the graph is loaded.
+    pub max_batch_size: usize,
+    /// How inputs of other sizes than the model's preferred one are handled.
+    pub resolution: Resolution,
+    /// Class names of the JSON output in class index order; defaults to the ImageNet labels.
+    pub labels: Option<Vec<String>>,
+    /// Saliency maps computed along with the scores; off by default, as they
+    /// take extra backward passes. Only SqueezeNet graphs support them; other
+    /// classifiers reject the setting with `UnsupportedOperation`.
+    pub saliency: Option<SaliencyConfig>,
+}
+
+impl Default for ClassificationConfig {
+    fn default() -> Self {
+        ClassificationConfig {
+            top_k: 5,
+            max_batch_size: 32,
+            resolution: Resolution::Resize,
+            labels: None,
+            saliency: None,
+        }
+    }
+}
+
+/// How input gradients are turned into a saliency map.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SaliencyMethod {
+    /// Gradient of the class score at the input.
+    #[default]
+    Vanilla,
+    /// Gradients averaged along the path from an all-zero input to the
+    /// input, times the input.
+    Integrated,
+}
+
+/// Gradient-based explanation of a classifier context's scores; SqueezeNet only.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SaliencyConfig {
+    pub method: SaliencyMethod,
+    /// Class to explain; defaults to the top class of each image.
+    pub class: Option<usize>,
+    /// Path steps of integrated gradients.
+    pub steps: usize,
+}
+
+impl Default for SaliencyConfig {
+    fn default() -> Self {
+        SaliencyConfig { method: SaliencyMethod::Vanilla, class: None, steps: 32 }
+    }
+}
+
+/// Input resolution policy of a classifier context.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    /// Bilinearly resize on the device to the model's preferred size (224x224).
+    #[default]
+    Resize,
+    /// Run the fully convolutional models at the input's own size.
+    Native,
+}
+
+/// Post-processing thresholds of an object-detection context.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DetectionConfig {
+    /// Minimum objectness times class score of a kept box.
+    pub score_threshold: f32,
+    /// Boxes of the same class overlapping more than this are suppressed.
+    pub iou_threshold: f32,
+    pub max_detections: usize,
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        DetectionConfig {
+            score_threshold: 0.25,
+            iou_threshold: 0.45,
+            max_detections: 100,
+        }
+    }
+}
+
+/// Architecture of a UNet segmentation model; must match the loaded weights.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SegmentationConfig {
+    pub num_classes: usize,
+    pub in_channels: usize,
+    /// Channels of the first level, doubled at every level below.
+    pub base_channels: usize,
+    /// Number of downsampling levels.
+    pub depth: usize,
+}
+
+impl Default for SegmentationConfig {
+    fn default() -> Self {
+        SegmentationConfig {
+            num_classes: 21,
+            in_channels: 3,
+            base_channels: 64,
+            depth: 4,
+        }
+    }
+}
+
+/// Which CLIP tower a context runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipMode {
+    /// `[N, 3, H, W]` `f32` images, normalized with CLIP's mean and std.
+    #[default]
+    Image,
+    /// `[N, seq]` token ids, or UTF-8 text if the model ships a tokenizer.
+    Text,
+}
+
+/// Per-context settings of a CLIP graph.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClipContextConfig {
+    pub mode: ClipMode,
+}
+
+/// Architecture of a CRNN text recognizer; must match the loaded weights.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OcrConfig {
+    /// Characters of the output classes in order; class 0 is the CTC blank.
+    pub charset: String,
+    /// Hidden size of each direction of the recurrent layers.
+    pub hidden_size: usize,
+    /// Default decoding of OCR contexts.
+    pub decoding: CtcDecodingConfig,
+}
+
+impl Default for OcrConfig {
+    fn default() -> Self {
+        OcrConfig {
+            charset: "0123456789abcdefghijklmnopqrstuvwxyz".to_string(),
+            hidden_size: 256,
+            decoding: CtcDecodingConfig::default(),
+        }
+    }
+}
+
+/// CTC decoding strategy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CtcDecoder {
+    /// Most likely class per step, with repeats and blanks collapsed.
+    #[default]
+    Greedy,
+    /// Prefix beam search over `beam_width` candidates.
+    Beam,
+}
+
+/// Per-context decoding settings of an OCR graph.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CtcDecodingConfig {
+    pub decoder: CtcDecoder,
+    pub beam_width: usize,
+}
+
+impl Default for CtcDecodingConfig {
+    fn default() -> Self {
+        CtcDecodingConfig {
+            decoder: CtcDecoder::Greedy,
+            beam_width: 8,
+        }
+    }
+}
+
+/// Recurrent cell type of a time-series model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecurrentCell {
+    #[default]
+    Lstm,
+    Gru,
+}
+
+/// Architecture of a stacked LSTM/GRU forecaster; must match the loaded weights.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecurrentConfig {
+    pub cell: RecurrentCell,
+    /// Features per timestep of the input.
+    pub input_size: usize,
+    pub hidden_size: usize,
+    pub num_layers: usize,
+    /// Features per timestep of the linear head on top of the last layer.
+    pub output_size: usize,
+}
+
+impl Default for RecurrentConfig {
+    fn default() -> Self {
+        RecurrentConfig {
+            cell: RecurrentCell::Lstm,
+            input_size: 1,
+            hidden_size: 64,
+            num_layers: 1,
+            output_size: 1,
+        }
+    }
+}
+
+/// Optimizer of a training session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OptimizerKind {
+    Sgd,
+    #[default]
+    Adam,
+}
+
+/// Settings of a fine-tuning session, given as JSON when it is created.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrainingConfig {
+    pub optimizer: OptimizerKind,
+    pub learning_rate: f64,
+    /// Momentum of SGD; plain SGD when unset.
+    pub momentum: Option<f64>,
+    /// Layers whose weights stay fixed, e.g. `["conv1", "fires"]` to retrain only the classifier.
+    pub frozen: Vec<String>,
+    /// Replaces the classifier with a fresh one of this many classes.
+    pub num_classes: Option<usize>,
+    /// Class names of the trained graph's JSON output, one per class; a new
+    /// head without them is labeled with class indices.
+    pub labels: Option<Vec<String>>,
+}
+
+impl Default for TrainingConfig {
+    fn default() -> Self {
+        TrainingConfig {
+            optimizer: OptimizerKind::Adam,
+            learning_rate: 1e-3,
+            momentum: None,
+            frozen: Vec::new(),
+            num_classes: None,
+            labels: None,
+        }
+    }
+}
+
+/// Applies a partial JSON object on top of `current`, keeping every field it does not mention.
+pub fn merge_config<T: Serialize + DeserializeOwned>(current: &T, json: &str) -> Result<T, ErrNo> {
+    let mut merged = serde_json::to_value(current).map_err(|_| ErrNo::RuntimeError)?;
+    let update: serde_json::Value = serde_json::from_str(json).map_err(|_| ErrNo::InvalidArgument)?;
+
+    match (merged.as_object_mut(), update) {
+        (Some(merged), serde_json::Value::Object(update)) => merged.extend(update),
+        _ => return Err(ErrNo::InvalidArgument),
+    }
+
+    serde_json::from_value(merged).map_err(|_| ErrNo::InvalidArgument)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_keys_fail_the_load() {
+        assert_eq!(GraphConfig::from_bytes(br#"{"cpu_backed": "cubecl"}"#), Err(ErrNo::InvalidArgument));
+        assert_eq!(GraphConfig::from_bytes(br#"{"detection": {"iou": 0.5}}"#), Err(ErrNo::InvalidArgument));
+
+        let config = GraphConfig::from_bytes(br#"{"cpu_backend": "cubecl"}"#).unwrap().unwrap();
+        assert_eq!(config.cpu_backend, CpuBackend::Cubecl);
+    }
+
+    #[test]
+    fn unknown_keys_fail_a_merge() {
+        let config = DetectionConfig::default();
+        assert_eq!(merge_config(&config, r#"{"iou": 0.5}"#), Err(ErrNo::InvalidArgument));
+        assert!(merge_config(&config, r#"{"iou_threshold": 0.5}"#).is_ok());
+    }
+}
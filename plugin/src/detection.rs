@@ -0,0 +1,298 @@
+use burn::prelude::Backend;
+use burn::tensor::{ElementConversion, Int, Tensor};
+use log::{debug, error, info};
+use yolox_burn::model::{weights, yolox::Yolox};
+use crate::config::{merge_config, DetectionConfig, ModelKind};
+use crate::ErrNo;
+
+const INPUT_DIM: usize = 4;
+
+/// Number of values per YOLOX anchor before the class scores: box (4) and objectness (1).
+const BOX_VALUES: usize = 5;
+
+/// Downsampling of the deepest YOLOX feature map; the upsampled pyramid levels
+/// only line up with their lateral inputs for image sides divisible by it.
+const MAX_STRIDE: usize = 32;
+
+pub struct DetectorModel<B: Backend> {
+    model: Yolox<B>,
+    config: DetectionConfig,
+    device: B::Device,
+}
+
+/// One detection in input pixel coordinates.
+#[derive(Debug, Clone, Copy)]
+pub struct Detection {
+    pub x1: f32,
+    pub y1: f32,
+    pub x2: f32,
+    pub y2: f32,
+    pub score: f32,
+    pub class_id: i64,
+}
+
+pub struct DetectorContext<B: Backend> {
+    pub input: Option<Tensor<B, INPUT_DIM>>,
+    pub detections: Option<Vec<Detection>>,
+    config: DetectionConfig,
+    device: B::Device,
+}
+
+impl<B: Backend> DetectorModel<B> {
+    /// Builds a YOLOX detector with COCO weights; the weights are downloaded
+    /// on first use and cached by `yolox-burn`.
+    pub fn new(kind: ModelKind, config: DetectionConfig, device: &B::Device) -> Option<Self> {
+        let model = match kind {
+            ModelKind::YoloxNano => Yolox::yolox_nano_pretrained(weights::YoloxNano::Coco, device),
+            ModelKind::YoloxTiny => Yolox::yolox_tiny_pretrained(weights::YoloxTiny::Coco, device),
+            ModelKind::YoloxS => Yolox::yolox_s_pretrained(weights::YoloxS::Coco, device),
+            _ => return None,
+        };
+
+        match model {
+            Ok(model) => {
+                info!("Loaded {:?} weights", kind);
+                Some(DetectorModel { model, config, device: device.clone() })
+            }
+            Err(err) => {
+                error!("Failed to load {:?} weights: {:?}", kind, err);
+                None
+            }
+        }
+    }
+
+    pub fn init_execution_context(&self) -> DetectorContext<B> {
+        DetectorContext {
+            input: None,
+            detections: None,
+            config: self.config.clone(),
+            device: self.device.clone(),
+        }
+    }
+
+    /// Runs the detector on a single `[1, 3, H, W]` image of 0-255 pixel values and
+    /// returns the detections that survive thresholding and class-aware NMS.
+    pub fn compute(&self, input: Tensor<B, INPUT_DIM>, config: &DetectionConfig) -> Result<Vec<Detection>, ErrNo> {
+        let [batch_size, _, _, _] = input.dims();
+        if batch_size != 1 {
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        // [1, anchors, 5 + classes]: cx, cy, w, h, objectness, class scores
+        let output = self.model.forward(input);
+        let [_, anchors, values] = output.dims();
+        let output = output.reshape([anchors, values]);
+
+        // score and threshold on the device so only candidates are downloaded
+        let boxes = output.clone().slice([0..anchors, 0..4]);
+        let objectness = output.clone().slice([0..anchors, 4..BOX_VALUES]);
+        let (class_scores, class_ids) = output
+            .slice([0..anchors, BOX_VALUES..values])
+            .max_dim_with_indices(1);
+        let scores = (objectness * class_scores).reshape([anchors]);
+
+        let keep = scores.clone().greater_elem(config.score_threshold);
+        let candidates = keep.clone().int().sum().into_scalar().elem::<i64>() as usize;
+        if candidates == 0 {
+            return Ok(Vec::new());
+        }
+
+        let indices: Tensor<B, 1, Int> = keep.nonzero().remove(0);
+        let boxes = to_vec(boxes.select(0, indices.clone()))?;
+        let scores = to_vec(scores.select(0, indices.clone()))?;
+        let class_ids = class_ids.reshape([anchors]).select(0, indices)
+            .into_data()
+            .convert::<i64>()
+            .to_vec::<i64>()
+            .map_err(|_| ErrNo::RuntimeError)?;
+
+        let detections = boxes
+            .chunks_exact(4)
+            .zip(scores)
+            .zip(class_ids)
+            .map(|((bbox, score), class_id)| Detection {
+                x1: bbox[0] - bbox[2] / 2.0,
+                y1: bbox[1] - bbox[3] / 2.0,
+                x2: bbox[0] + bbox[2] / 2.0,
+                y2: bbox[1] + bbox[3] / 2.0,
+                score,
+                class_id,
+            })
+            .collect();
+
+        let detections = non_maximum_suppression(detections, config.iou_threshold, config.max_detections);
+        debug!("Detections: {} candidates, {} after NMS", candidates, detections.len());
+        Ok(detections)
+    }
+}
+
+fn to_vec<B: Backend, const D: usize>(tensor: Tensor<B, D>) -> Result<Vec<f32>, ErrNo> {
+    tensor.into_data().convert::<f32>().to_vec().map_err(|_| ErrNo::RuntimeError)
+}
+
+fn iou(a: &Detection, b: &Detection) -> f32 {
+    let width = (a.x2.min(b.x2) - a.x1.max(b.x1)).max(0.0);
+    let height = (a.y2.min(b.y2) - a.y1.max(b.y1)).max(0.0);
+    let intersection = width * height;
+    let area = |d: &Detection| (d.x2 - d.x1) * (d.y2 - d.y1);
+    let union = area(a) + area(b) - intersection;
+    if union <= 0.0 { 0.0 } else { intersection / union }
+}
+
+/// Greedy NMS that only suppresses overlapping boxes of the same class.
+fn non_maximum_suppression(mut detections: Vec<Detection>, iou_threshold: f32, max_detections: usize) -> Vec<Detection> {
+    detections.sort_unstable_by(|a, b| b.score.total_cmp(&a.score));
+
+    let mut kept: Vec<Detection> = Vec::new();
+    for detection in detections {
+        if kept.len() >= max_detections {
+            break;
+        }
+        let suppressed = kept
+            .iter()
+            .any(|other| other.class_id == detection.class_id && iou(other, &detection) > iou_threshold);
+        if !suppressed {
+            kept.push(detection);
+        }
+    }
+    kept
+}
+
+impl<B: Backend> DetectorContext<B> {
+    /// Takes a single `[1, 3, H, W]` image with sides divisible by `MAX_STRIDE`.
+    pub fn set_input(&mut self, input: &[f32], dimens: [usize; INPUT_DIM]) -> Result<(), ErrNo> {
+        let [batch_size, channels, height, width] = dimens;
+        if batch_size != 1 || channels != 3 || height == 0 || width == 0 || height % MAX_STRIDE != 0 || width % MAX_STRIDE != 0 {
+            error!("YOLOX expects a [1, 3, H, W] image with sides divisible by {}, got {:?}", MAX_STRIDE, dimens);
+            return Err(ErrNo::InvalidArgument);
+        }
+        let tensor = Tensor::<B, 1>::from_data(input, &self.device).reshape(dimens);
+        self.input = Some(tensor);
+        Ok(())
+    }
+
+    /// Overrides thresholds with a partial JSON object.
+    pub fn configure(&mut self, json: &str) -> Result<(), ErrNo> {
+        self.config = merge_config(&self.config, json)?;
+        Ok(())
+    }
+
+    pub fn compute(&mut self, model: &DetectorModel<B>) -> Result<(), ErrNo> {
+        let input = self.input.clone().ok_or(ErrNo::InvalidArgument)?;
+        self.detections = Some(model.compute(input, &self.config)?);
+        Ok(())
+    }
+
+    /// Output 0 is the `[N, 6]` detection table; 1, 2 and 3 hold the boxes `[N, 4]`,
+    /// class ids `[N]` as `i64` and scores `[N]` on their own.
+    pub fn get_output(&self, index: i32) -> Result<Vec<u8>, ErrNo> {
+        let detections = self.detections.as_ref().ok_or(ErrNo::RuntimeError)?;
+        let output = match index {
+            0 => {
+                let rows: Vec<f32> = detections
+                    .iter()
+                    .flat_map(|d| [d.x1, d.y1, d.x2, d.y2, d.score, d.class_id as f32])
+                    .collect();
+                bytemuck::cast_slice(&rows).to_vec()
+            }
+            1 => {
+                let boxes: Vec<f32> = detections.iter().flat_map(|d| [d.x1, d.y1, d.x2, d.y2]).collect();
+                bytemuck::cast_slice(&boxes).to_vec()
+            }
+            2 => {
+                let class_ids: Vec<i64> = detections.iter().map(|d| d.class_id).collect();
+                bytemuck::cast_slice(&class_ids).to_vec()
+            }
+            3 => {
+                let scores: Vec<f32> = detections.iter().map(|d| d.score).collect();
+                bytemuck::cast_slice(&scores).to_vec()
+            }
+            _ => return Err(ErrNo::InvalidArgument),
+        };
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detection(x1: f32, y1: f32, x2: f32, y2: f32, score: f32, class_id: i64) -> Detection {
+        Detection { x1, y1, x2, y2, score, class_id }
+    }
+
+    #[test]
+    fn iou_of_overlapping_boxes() {
+        let a = detection(0.0, 0.0, 10.0, 10.0, 1.0, 0);
+        let b = detection(5.0, 0.0, 15.0, 10.0, 1.0, 0);
+        assert!((iou(&a, &b) - 50.0 / 150.0).abs() < 1e-6);
+        assert_eq!(iou(&a, &a), 1.0);
+        assert_eq!(iou(&a, &detection(20.0, 20.0, 30.0, 30.0, 1.0, 0)), 0.0);
+    }
+
+    #[test]
+    fn iou_of_zero_area_boxes_is_zero() {
+        let point = detection(5.0, 5.0, 5.0, 5.0, 1.0, 0);
+        let line = detection(0.0, 5.0, 10.0, 5.0, 1.0, 0);
+        let square = detection(0.0, 0.0, 10.0, 10.0, 1.0, 0);
+        assert_eq!(iou(&point, &point), 0.0);
+        assert_eq!(iou(&point, &square), 0.0);
+        assert_eq!(iou(&line, &square), 0.0);
+    }
+
+    #[test]
+    fn nms_suppresses_overlapping_boxes_of_the_same_class() {
+        let detections = vec![
+            detection(0.0, 0.0, 10.0, 10.0, 0.6, 1),
+            detection(1.0, 1.0, 11.0, 11.0, 0.9, 1),
+        ];
+        let kept = non_maximum_suppression(detections, 0.5, 100);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].score, 0.9);
+    }
+
+    #[test]
+    fn nms_keeps_overlapping_boxes_of_different_classes() {
+        let detections = vec![
+            detection(0.0, 0.0, 10.0, 10.0, 0.6, 1),
+            detection(1.0, 1.0, 11.0, 11.0, 0.9, 2),
+        ];
+        let kept = non_maximum_suppression(detections, 0.5, 100);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0].class_id, 2);
+        assert_eq!(kept[1].class_id, 1);
+    }
+
+    #[test]
+    fn nms_keeps_zero_area_boxes() {
+        let detections = vec![
+            detection(5.0, 5.0, 5.0, 5.0, 0.9, 0),
+            detection(5.0, 5.0, 5.0, 5.0, 0.8, 0),
+            detection(0.0, 0.0, 10.0, 10.0, 0.7, 0),
+        ];
+        assert_eq!(non_maximum_suppression(detections, 0.5, 100).len(), 3);
+    }
+
+    #[test]
+    fn inputs_yolox_cannot_take_are_rejected() {
+        let mut context = DetectorContext::<burn::backend::NdArray<f32>> {
+            input: None,
+            detections: None,
+            config: DetectionConfig::default(),
+            device: Default::default(),
+        };
+        for dimens in [[1, 1, 64, 64], [2, 3, 64, 64], [1, 3, 0, 64], [1, 3, 8, 8], [1, 3, 64, 100]] {
+            let input = vec![0.0; dimens.iter().product()];
+            assert_eq!(context.set_input(&input, dimens), Err(ErrNo::InvalidArgument), "{:?}", dimens);
+        }
+        assert_eq!(context.set_input(&vec![0.0; 3 * 64 * 96], [1, 3, 64, 96]), Ok(()));
+    }
+
+    #[test]
+    fn nms_stops_at_max_detections() {
+        let detections = (0..5)
+            .map(|i| detection(20.0 * i as f32, 0.0, 20.0 * i as f32 + 10.0, 10.0, 0.5, 0))
+            .collect();
+        assert_eq!(non_maximum_suppression(detections, 0.5, 3).len(), 3);
+    }
+}
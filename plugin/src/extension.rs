@@ -0,0 +1,253 @@
+use wasmedge_plugin_sdk::{
+    error::CoreError,
+    memory::Memory,
+    module::{PluginModule, SyncInstanceRef},
+    types::{ValType, WasmVal},
+};
+use crate::{init_plugin, ErrNo, WASI_NN};
+
+/// Host functions beyond the wasi-nn spec, imported by guests from `wasi_nn_burn`.
+/// Handles are shared with `wasi_ephemeral_nn`.
+pub fn create_extension_module() -> PluginModule<()> {
+    init_plugin();
+
+    fn tokenize<'a>(
+        _inst_ref: &'a mut SyncInstanceRef,
+        memory: &'a mut Memory,
+        _data: &'a mut (),
+        args: Vec<WasmVal>,
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        if let [WasmVal::I32(graph_handle),
+                WasmVal::I32(text_ptr),
+                WasmVal::I32(text_len),
+                WasmVal::I32(ids_ptr),
+                WasmVal::I32(mask_ptr),
+                WasmVal::I32(max_tokens),
+                WasmVal::I32(written_tokens_ptr)] = &args[..]
+        {
+            let wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.tokenize(graph_handle, text_ptr, text_len, ids_ptr, mask_ptr, max_tokens, written_tokens_ptr, memory)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
+    }
+
+    fn detokenize<'a>(
+        _inst_ref: &'a mut SyncInstanceRef,
+        memory: &'a mut Memory,
+        _data: &'a mut (),
+        args: Vec<WasmVal>,
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        if let [WasmVal::I32(graph_handle),
+                WasmVal::I32(ids_ptr),
+                WasmVal::I32(ids_len),
+                WasmVal::I32(text_ptr),
+                WasmVal::I32(text_max_size),
+                WasmVal::I32(written_len_ptr)] = &args[..]
+        {
+            let wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.detokenize(graph_handle, ids_ptr, ids_len, text_ptr, text_max_size, written_len_ptr, memory)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
+    }
+
+    fn set_context_config<'a>(
+        _inst_ref: &'a mut SyncInstanceRef,
+        memory: &'a mut Memory,
+        _data: &'a mut (),
+        args: Vec<WasmVal>,
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        if let [WasmVal::I32(ctx_handle),
+                WasmVal::I32(config_ptr),
+                WasmVal::I32(config_len)] = &args[..]
+        {
+            let wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.set_context_config(ctx_handle, config_ptr, config_len, memory)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
+    }
+
+    fn get_output_shape<'a>(
+        _inst_ref: &'a mut SyncInstanceRef,
+        memory: &'a mut Memory,
+        _data: &'a mut (),
+        args: Vec<WasmVal>,
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        if let [WasmVal::I32(ctx_handle),
+                WasmVal::I32(output_index),
+                WasmVal::I32(dims_ptr),
+                WasmVal::I32(dims_max_len),
+                WasmVal::I32(dims_written_ptr)] = &args[..]
+        {
+            let wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.get_output_shape(ctx_handle, output_index, dims_ptr, dims_max_len, dims_written_ptr, memory)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
+    }
+
+    fn get_graph_metadata<'a>(
+        _inst_ref: &'a mut SyncInstanceRef,
+        memory: &'a mut Memory,
+        _data: &'a mut (),
+        args: Vec<WasmVal>,
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        if let [WasmVal::I32(graph_handle),
+                WasmVal::I32(metadata_ptr),
+                WasmVal::I32(metadata_max_size),
+                WasmVal::I32(written_len_ptr)] = &args[..]
+        {
+            let wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.get_graph_metadata(graph_handle, metadata_ptr, metadata_max_size, written_len_ptr, memory)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
+    }
+
+    fn get_backend_report<'a>(
+        _inst_ref: &'a mut SyncInstanceRef,
+        memory: &'a mut Memory,
+        _data: &'a mut (),
+        args: Vec<WasmVal>,
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        if let [WasmVal::I32(report_ptr),
+                WasmVal::I32(report_max_size),
+                WasmVal::I32(written_len_ptr)] = &args[..]
+        {
+            let wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.get_backend_report(report_ptr, report_max_size, written_len_ptr, memory)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
+    }
+
+    fn create_training_session<'a>(
+        _inst_ref: &'a mut SyncInstanceRef,
+        memory: &'a mut Memory,
+        _data: &'a mut (),
+        args: Vec<WasmVal>,
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        if let [WasmVal::I32(graph_handle),
+                WasmVal::I32(config_ptr),
+                WasmVal::I32(config_len),
+                WasmVal::I32(session_handle_ptr)] = &args[..]
+        {
+            let mut wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.create_training_session(graph_handle, config_ptr, config_len, session_handle_ptr, memory)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
+    }
+
+    fn train_step<'a>(
+        _inst_ref: &'a mut SyncInstanceRef,
+        memory: &'a mut Memory,
+        _data: &'a mut (),
+        args: Vec<WasmVal>,
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        if let [WasmVal::I32(session_handle),
+                WasmVal::I32(input_tensor_ptr),
+                WasmVal::I32(labels_ptr),
+                WasmVal::I32(labels_len),
+                WasmVal::I32(loss_ptr)] = &args[..]
+        {
+            let mut wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.train_step(session_handle, input_tensor_ptr, labels_ptr, labels_len, loss_ptr, memory)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
+    }
+
+    fn finish_training<'a>(
+        _inst_ref: &'a mut SyncInstanceRef,
+        memory: &'a mut Memory,
+        _data: &'a mut (),
+        args: Vec<WasmVal>,
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        if let [WasmVal::I32(session_handle),
+                WasmVal::I32(graph_handle_ptr)] = &args[..]
+        {
+            let mut wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.finish_training(session_handle, graph_handle_ptr, memory)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
+    }
+
+    let mut module = PluginModule::create("wasi_nn_burn", ()).unwrap();
+    module
+        .add_func(
+            "tokenize",
+            (vec![ValType::I32; 7], vec![ValType::I32]),
+            tokenize,
+        )
+        .unwrap();
+    module
+        .add_func(
+            "detokenize",
+            (vec![ValType::I32; 6], vec![ValType::I32]),
+            detokenize,
+        )
+        .unwrap();
+    module
+        .add_func(
+            "set_context_config",
+            (vec![ValType::I32; 3], vec![ValType::I32]),
+            set_context_config,
+        )
+        .unwrap();
+    module
+        .add_func(
+            "get_output_shape",
+            (vec![ValType::I32; 5], vec![ValType::I32]),
+            get_output_shape,
+        )
+        .unwrap();
+    module
+        .add_func(
+            "get_graph_metadata",
+            (vec![ValType::I32; 4], vec![ValType::I32]),
+            get_graph_metadata,
+        )
+        .unwrap();
+    module
+        .add_func(
+            "get_backend_report",
+            (vec![ValType::I32; 3], vec![ValType::I32]),
+            get_backend_report,
+        )
+        .unwrap();
+    module
+        .add_func(
+            "create_training_session",
+            (vec![ValType::I32; 4], vec![ValType::I32]),
+            create_training_session,
+        )
+        .unwrap();
+    module
+        .add_func(
+            "train_step",
+            (vec![ValType::I32; 5], vec![ValType::I32]),
+            train_step,
+        )
+        .unwrap();
+    module
+        .add_func(
+            "finish_training",
+            (vec![ValType::I32; 2], vec![ValType::I32]),
+            finish_training,
+        )
+        .unwrap();
+    module
+}
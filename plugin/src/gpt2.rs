@@ -0,0 +1,568 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use burn::module::Module;
+use burn::nn::{Embedding, EmbeddingConfig, LayerNorm, LayerNormConfig, Linear, LinearConfig};
+use burn::prelude::Backend;
+use burn::tensor::activation::{gelu, softmax};
+use burn::tensor::{Bool, Int, Tensor};
+use burn_import::safetensors::AdapterType;
+use log::{debug, error, info};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use crate::config::{merge_config, GenerationConfig};
+use crate::ErrNo;
+use crate::tokenizer::TextTokenizer;
+use crate::weights::load_safetensors;
+
+/// Key remaps from HuggingFace GPT-2 checkpoints to `Gpt2` field names.
+/// GPT-2 stores its projections as `Conv1D` with `[in, out]` weights, which is
+/// already burn's `Linear` layout, so no adapter is used and only the layer
+/// norm parameters need renaming.
+const GPT2_REMAPS: [(&str, &str); 4] = [
+    ("^transformer\\.", ""),
+    ("^h\\.([0-9]+)", "blocks.$1"),
+    ("(ln_[12f])\\.weight$", "$1.gamma"),
+    ("(ln_[12f])\\.bias$", "$1.beta"),
+];
+
+/// Large negative score used to hide future positions from attention.
+const MASKED_SCORE: f32 = -1.0e4;
+
+/// Subset of a HuggingFace GPT-2 `config.json`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Gpt2Config {
+    pub vocab_size: usize,
+    pub n_positions: usize,
+    pub n_embd: usize,
+    pub n_layer: usize,
+    pub n_head: usize,
+    #[serde(default = "default_layer_norm_epsilon")]
+    pub layer_norm_epsilon: f64,
+    #[serde(default = "default_eos_token_id")]
+    pub eos_token_id: i64,
+}
+
+fn default_layer_norm_epsilon() -> f64 {
+    1e-5
+}
+
+fn default_eos_token_id() -> i64 {
+    50256
+}
+
+#[derive(Module, Debug)]
+pub struct Gpt2Attention<B: Backend> {
+    c_attn: Linear<B>,
+    c_proj: Linear<B>,
+    n_head: usize,
+}
+
+#[derive(Module, Debug)]
+pub struct Gpt2Mlp<B: Backend> {
+    c_fc: Linear<B>,
+    c_proj: Linear<B>,
+}
+
+#[derive(Module, Debug)]
+pub struct Gpt2Block<B: Backend> {
+    ln_1: LayerNorm<B>,
+    attn: Gpt2Attention<B>,
+    ln_2: LayerNorm<B>,
+    mlp: Gpt2Mlp<B>,
+}
+
+#[derive(Module, Debug)]
+pub struct Gpt2<B: Backend> {
+    wte: Embedding<B>,
+    wpe: Embedding<B>,
+    blocks: Vec<Gpt2Block<B>>,
+    ln_f: LayerNorm<B>,
+}
+
+/// Keys and values of all past positions of one layer, `[batch, heads, seq, head_dim]`.
+pub struct LayerCache<B: Backend> {
+    key: Option<Tensor<B, 4>>,
+    value: Option<Tensor<B, 4>>,
+}
+
+impl<B: Backend> LayerCache<B> {
+    pub fn new() -> Self {
+        LayerCache { key: None, value: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.key.as_ref().map(|key| key.dims()[2]).unwrap_or(0)
+    }
+}
+
+impl Gpt2Config {
+    pub fn init<B: Backend>(&self, device: &B::Device) -> Gpt2<B> {
+        let layer_norm = || LayerNormConfig::new(self.n_embd).with_epsilon(self.layer_norm_epsilon);
+
+        let blocks = (0..self.n_layer)
+            .map(|_| Gpt2Block {
+                ln_1: layer_norm().init(device),
+                attn: Gpt2Attention {
+                    c_attn: LinearConfig::new(self.n_embd, 3 * self.n_embd).init(device),
+                    c_proj: LinearConfig::new(self.n_embd, self.n_embd).init(device),
+                    n_head: self.n_head,
+                },
+                ln_2: layer_norm().init(device),
+                mlp: Gpt2Mlp {
+                    c_fc: LinearConfig::new(self.n_embd, 4 * self.n_embd).init(device),
+                    c_proj: LinearConfig::new(4 * self.n_embd, self.n_embd).init(device),
+                },
+            })
+            .collect();
+
+        Gpt2 {
+            wte: EmbeddingConfig::new(self.vocab_size, self.n_embd).init(device),
+            wpe: EmbeddingConfig::new(self.n_positions, self.n_embd).init(device),
+            blocks,
+            ln_f: layer_norm().init(device),
+        }
+    }
+}
+
+/// `[seq, total]` mask that is true where a query at absolute position
+/// `offset + i` would look at a later key position.
+fn causal_mask<B: Backend>(offset: usize, seq: usize, total: usize, device: &B::Device) -> Tensor<B, 2, Bool> {
+    let rows = Tensor::<B, 1, Int>::arange(offset as i64..(offset + seq) as i64, device)
+        .reshape([seq, 1])
+        .expand([seq, total]);
+    let cols = Tensor::<B, 1, Int>::arange(0..total as i64, device)
+        .reshape([1, total])
+        .expand([seq, total]);
+    cols.greater(rows)
+}
+
+impl<B: Backend> Gpt2Attention<B> {
+    pub fn forward(&self, x: Tensor<B, 3>, cache: &mut LayerCache<B>) -> Tensor<B, 3> {
+        let [batch_size, seq_length, n_embd] = x.dims();
+        let head_dim = n_embd / self.n_head;
+        let offset = cache.len();
+
+        let split_heads = |tensor: Tensor<B, 3>| {
+            tensor.reshape([batch_size, seq_length, self.n_head, head_dim]).swap_dims(1, 2)
+        };
+        let mut qkv = self.c_attn.forward(x).chunk(3, 2);
+        let value = split_heads(qkv.pop().unwrap());
+        let key = split_heads(qkv.pop().unwrap());
+        let query = split_heads(qkv.pop().unwrap());
+
+        // append the new positions to the cache
+        let key = match cache.key.take() {
+            Some(past) => Tensor::cat(vec![past, key], 2),
+            None => key,
+        };
+        let value = match cache.value.take() {
+            Some(past) => Tensor::cat(vec![past, value], 2),
+            None => value,
+        };
+        cache.key = Some(key.clone());
+        cache.value = Some(value.clone());
+
+        let total = offset + seq_length;
+        let mask = causal_mask::<B>(offset, seq_length, total, &query.device())
+            .unsqueeze::<4>()
+            .expand([batch_size, self.n_head, seq_length, total]);
+
+        let scores = query
+            .matmul(key.swap_dims(2, 3))
+            .div_scalar((head_dim as f32).sqrt())
+            .mask_fill(mask, MASKED_SCORE);
+
+        let context = softmax(scores, 3)
+            .matmul(value)
+            .swap_dims(1, 2)
+            .reshape([batch_size, seq_length, n_embd]);
+
+        self.c_proj.forward(context)
+    }
+}
+
+impl<B: Backend> Gpt2Block<B> {
+    pub fn forward(&self, x: Tensor<B, 3>, cache: &mut LayerCache<B>) -> Tensor<B, 3> {
+        let x = x.clone() + self.attn.forward(self.ln_1.forward(x), cache);
+        let hidden = gelu(self.mlp.c_fc.forward(self.ln_2.forward(x.clone())));
+        x + self.mlp.c_proj.forward(hidden)
+    }
+}
+
+impl<B: Backend> Gpt2<B> {
+    /// Feeds `[batch, seq]` new tokens and returns the logits `[batch, vocab]` of the last position.
+    pub fn forward(&self, input_ids: Tensor<B, 2, Int>, cache: &mut [LayerCache<B>]) -> Tensor<B, 2> {
+        let [batch_size, seq_length] = input_ids.dims();
+        let device = input_ids.device();
+        let offset = cache.first().map(LayerCache::len).unwrap_or(0);
+
+        let positions = Tensor::<B, 1, Int>::arange(offset as i64..(offset + seq_length) as i64, &device)
+            .unsqueeze::<2>()
+            .repeat_dim(0, batch_size);
+
+        let mut hidden = self.wte.forward(input_ids) + self.wpe.forward(positions);
+        for (block, layer_cache) in self.blocks.iter().zip(cache.iter_mut()) {
+            hidden = block.forward(hidden, layer_cache);
+        }
+        let hidden = self.ln_f.forward(hidden);
+
+        let [_, _, n_embd] = hidden.dims();
+        let last = hidden
+            .slice([0..batch_size, seq_length - 1..seq_length, 0..n_embd])
+            .reshape([batch_size, n_embd]);
+
+        // the language model head shares its weights with the token embedding
+        last.matmul(self.wte.weight.val().transpose())
+    }
+}
+
+pub struct Gpt2Model<B: Backend> {
+    model: Gpt2<B>,
+    config: Gpt2Config,
+    generation: GenerationConfig,
+    tokenizer: Option<Arc<TextTokenizer>>,
+    device: B::Device,
+}
+
+pub struct GenerationContext<B: Backend> {
+    prompt: Vec<i64>,
+    generated: Vec<i64>,
+    cache: Vec<LayerCache<B>>,
+    config: GenerationConfig,
+    rng: StdRng,
+    finished: bool,
+    /// Generated tokens before the window `get_output_single` decodes again.
+    prefix_offset: usize,
+    /// Generated tokens whose text was already handed out by `get_output_single`.
+    read_offset: usize,
+    vocab_size: usize,
+    tokenizer: Option<Arc<TextTokenizer>>,
+    device: B::Device,
+}
+
+impl<B: Backend> Gpt2Model<B> {
+    /// Loads a HuggingFace GPT-2 directory holding `config.json`,
+    /// `model.safetensors` and optionally `tokenizer.json`.
+    pub fn new(path: &Path, generation: GenerationConfig, device: &B::Device) -> Result<Self, ErrNo> {
+        let config: Gpt2Config = fs::read_to_string(path.join("config.json"))
+            .map_err(|err| err.to_string())
+            .and_then(|json| serde_json::from_str(&json).map_err(|err| err.to_string()))
+            .map_err(|err| {
+                error!("Failed to read GPT-2 config in {}: {}", path.display(), err);
+                ErrNo::NotFound
+            })?;
+
+        let record = load_safetensors::<B, _>(
+            &path.join("model.safetensors"),
+            &GPT2_REMAPS,
+            AdapterType::NoAdapter,
+            device,
+        ).map_err(|err| {
+            error!("Failed to load GPT-2 weights in {}: {:?}", path.display(), err);
+            ErrNo::RuntimeError
+        })?;
+
+        info!("Loaded GPT-2: {} layers, {} positions", config.n_layer, config.n_positions);
+
+        let model = config.init::<B>(device).load_record(record);
+        let tokenizer = TextTokenizer::from_dir(path).map(Arc::new);
+
+        Ok(Gpt2Model { model, config, generation, tokenizer, device: device.clone() })
+    }
+
+    pub fn tokenizer(&self) -> Option<&TextTokenizer> {
+        self.tokenizer.as_deref()
+    }
+
+    pub fn init_execution_context(&self) -> GenerationContext<B> {
+        GenerationContext {
+            prompt: Vec::new(),
+            generated: Vec::new(),
+            cache: Vec::new(),
+            config: self.generation.clone(),
+            rng: new_rng(self.generation.seed),
+            finished: false,
+            prefix_offset: 0,
+            read_offset: 0,
+            vocab_size: self.config.vocab_size,
+            tokenizer: self.tokenizer.clone(),
+            device: self.device.clone(),
+        }
+    }
+}
+
+/// The text a stream has not handed out yet: what `text` adds to the already
+/// streamed `prefix`. `None` while nothing was added, or while the addition
+/// ends in an incomplete UTF-8 character and more tokens may complete it.
+fn new_text<'a>(prefix: &str, text: &'a str, finished: bool) -> Option<&'a str> {
+    text.get(prefix.len()..)
+        .filter(|piece| !piece.is_empty() && (finished || !piece.ends_with('\u{FFFD}')))
+}
+
+fn new_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+impl<B: Backend> GenerationContext<B> {
+    /// Takes a single `[1, seq]` prompt; generation does not batch prompts.
+    pub fn set_prompt_tokens(&mut self, tokens: &[i64], dimens: [usize; 2]) -> Result<(), ErrNo> {
+        let [batch_size, seq_length] = dimens;
+        if batch_size != 1 {
+            error!("Prompts are generated one at a time, got a batch of {}", batch_size);
+            return Err(ErrNo::InvalidArgument);
+        }
+        if tokens.is_empty() || tokens.len() != seq_length {
+            return Err(ErrNo::InvalidArgument);
+        }
+        if let Some(token) = tokens.iter().find(|&&token| token < 0 || token as usize >= self.vocab_size) {
+            error!("Prompt token {} is outside the vocabulary of {}", token, self.vocab_size);
+            return Err(ErrNo::InvalidArgument);
+        }
+        self.prompt = tokens.to_vec();
+        self.reset();
+        Ok(())
+    }
+
+    pub fn set_prompt_text(&mut self, text: &[u8]) -> Result<(), ErrNo> {
+        let tokenizer = self.tokenizer.as_ref().ok_or(ErrNo::UnsupportedOperation)?;
+        let text = std::str::from_utf8(text).map_err(|_| ErrNo::InvalidEncoding)?;
+        let (tokens, _) = tokenizer.encode(text, usize::MAX)?;
+        self.set_prompt_tokens(&tokens, [1, tokens.len()])
+    }
+
+    /// Overrides sampling settings with a partial JSON object.
+    pub fn configure(&mut self, json: &str) -> Result<(), ErrNo> {
+        self.config = merge_config(&self.config, json)?;
+        self.rng = new_rng(self.config.seed);
+        Ok(())
+    }
+
+    /// Drops the KV cache and generated tokens, keeping the prompt.
+    pub fn reset(&mut self) {
+        self.generated.clear();
+        self.cache.clear();
+        self.finished = false;
+        self.prefix_offset = 0;
+        self.read_offset = 0;
+        self.rng = new_rng(self.config.seed);
+    }
+
+    /// Generates one token; fails with `EndOfSequence` once generation is over.
+    pub fn step(&mut self, model: &Gpt2Model<B>) -> Result<i64, ErrNo> {
+        if self.finished || self.generated.len() >= self.config.max_tokens {
+            self.finished = true;
+            return Err(ErrNo::EndOfSequence);
+        }
+
+        // the first step feeds the whole prompt, later ones only the newest token
+        let tokens = match self.generated.last() {
+            Some(&token) if !self.cache.is_empty() => vec![token],
+            _ => self.prompt.clone(),
+        };
+        if tokens.is_empty() {
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let position = self.cache.first().map(LayerCache::len).unwrap_or(0);
+        if position + tokens.len() > model.config.n_positions {
+            return Err(if position == 0 { ErrNo::PromptTooLong } else { ErrNo::ContextFull });
+        }
+
+        if self.cache.is_empty() {
+            self.cache = (0..model.config.n_layer).map(|_| LayerCache::new()).collect();
+        }
+
+        let input = Tensor::<B, 1, Int>::from_data(tokens.as_slice(), &self.device)
+            .reshape([1, tokens.len()]);
+        let logits = model.model.forward(input, &mut self.cache)
+            .into_data()
+            .convert::<f32>()
+            .to_vec::<f32>()
+            .map_err(|_| ErrNo::RuntimeError)?;
+
+        let token = self.sample(&logits);
+        if token == model.config.eos_token_id {
+            self.finished = true;
+            return Err(ErrNo::EndOfSequence);
+        }
+
+        self.generated.push(token);
+        Ok(token)
+    }
+
+    /// Runs generation to completion from the current prompt.
+    pub fn compute(&mut self, model: &Gpt2Model<B>) -> Result<(), ErrNo> {
+        self.reset();
+        loop {
+            match self.step(model) {
+                Ok(_) => {}
+                Err(ErrNo::EndOfSequence) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        debug!("Generated {} tokens", self.generated.len());
+        Ok(())
+    }
+
+    /// Output 0 is the generated UTF-8 text, output 1 the generated token ids as `i64`.
+    pub fn get_output(&self, index: i32) -> Result<Vec<u8>, ErrNo> {
+        match index {
+            0 => Ok(self.decode(&self.generated)?.into_bytes()),
+            1 => Ok(bytemuck::cast_slice(&self.generated).to_vec()),
+            _ => Err(ErrNo::InvalidArgument),
+        }
+    }
+
+    /// Like `get_output`, but only for what was generated since the previous call
+    /// (index 0) or for the latest token (index 1).
+    pub fn get_output_single(&mut self, index: i32) -> Result<Vec<u8>, ErrNo> {
+        match index {
+            0 => {
+                // decode the new tokens along with the previous ones, as tokens can
+                // merge with their neighbours; text ending in an incomplete UTF-8
+                // character is held back until the rest of it is generated
+                let prefix = self.decode(&self.generated[self.prefix_offset..self.read_offset])?;
+                let text = self.decode(&self.generated[self.prefix_offset..])?;
+                let piece = match new_text(&prefix, &text, self.finished) {
+                    Some(piece) => piece.to_owned(),
+                    None => return Ok(Vec::new()),
+                };
+                self.prefix_offset = self.read_offset;
+                self.read_offset = self.generated.len();
+                Ok(piece.into_bytes())
+            }
+            1 => {
+                let token = self.generated.last().ok_or(ErrNo::RuntimeError)?;
+                Ok(token.to_le_bytes().to_vec())
+            }
+            _ => Err(ErrNo::InvalidArgument),
+        }
+    }
+
+    fn decode(&self, tokens: &[i64]) -> Result<String, ErrNo> {
+        let tokenizer = self.tokenizer.as_ref().ok_or(ErrNo::UnsupportedOperation)?;
+        tokenizer.decode(tokens)
+    }
+
+    /// Temperature, top-k and top-p (nucleus) sampling over the vocabulary.
+    fn sample(&mut self, logits: &[f32]) -> i64 {
+        if self.config.temperature <= 0.0 {
+            return logits
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .map(|(index, _)| index as i64)
+                .unwrap_or(0);
+        }
+
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let mut candidates: Vec<(usize, f32)> = logits
+            .iter()
+            .enumerate()
+            .map(|(index, &logit)| (index, ((logit - max) / self.config.temperature).exp()))
+            .collect();
+        candidates.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+
+        if let Some(top_k) = self.config.top_k {
+            candidates.truncate(top_k.max(1));
+        }
+
+        if let Some(top_p) = self.config.top_p {
+            let total: f32 = candidates.iter().map(|(_, weight)| weight).sum();
+            let mut cumulative = 0.0;
+            let mut keep = 0;
+            for (_, weight) in &candidates {
+                cumulative += weight / total;
+                keep += 1;
+                if cumulative >= top_p {
+                    break;
+                }
+            }
+            candidates.truncate(keep.max(1));
+        }
+
+        let total: f32 = candidates.iter().map(|(_, weight)| weight).sum();
+        let mut threshold = self.rng.gen::<f32>() * total;
+        for (index, weight) in &candidates {
+            threshold -= weight;
+            if threshold <= 0.0 {
+                return *index as i64;
+            }
+        }
+        candidates.last().map(|(index, _)| *index as i64).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::backend::NdArray;
+    use crate::tokenizer::tests::tokenizer;
+    use super::*;
+
+    type TestBackend = NdArray<f32>;
+
+    fn context(generated: Vec<i64>) -> GenerationContext<TestBackend> {
+        GenerationContext {
+            prompt: vec![2],
+            generated,
+            cache: Vec::new(),
+            config: GenerationConfig::default(),
+            rng: new_rng(Some(0)),
+            finished: false,
+            prefix_offset: 0,
+            read_offset: 0,
+            vocab_size: 4,
+            tokenizer: Some(Arc::new(tokenizer())),
+            device: Default::default(),
+        }
+    }
+
+    #[test]
+    fn streaming_hands_out_each_token_once() {
+        let mut context = context(vec![2]);
+        assert_eq!(context.get_output_single(0).unwrap(), b"hello");
+        assert_eq!(context.get_output_single(0).unwrap(), b"");
+
+        context.generated.push(3);
+        assert_eq!(context.get_output_single(0).unwrap(), b" world");
+        assert_eq!((context.prefix_offset, context.read_offset), (1, 2));
+
+        context.generated.push(2);
+        assert_eq!(context.get_output_single(0).unwrap(), b" hello");
+        assert_eq!(context.get_output(0).unwrap(), b"hello world hello");
+    }
+
+    #[test]
+    fn incomplete_characters_are_held_back_until_finished() {
+        assert_eq!(new_text("ab", "abc", false), Some("c"));
+        assert_eq!(new_text("ab", "ab", false), None);
+        assert_eq!(new_text("ab", "ab\u{FFFD}", false), None);
+        assert_eq!(new_text("ab", "ab\u{FFFD}", true), Some("\u{FFFD}"));
+        assert_eq!(new_text("ab", "ab\u{e9}", false), Some("\u{e9}"));
+    }
+
+    #[test]
+    fn batched_prompts_are_rejected() {
+        let mut context = context(Vec::new());
+        assert_eq!(context.set_prompt_tokens(&[2, 3, 2, 3], [2, 2]), Err(ErrNo::InvalidArgument));
+        assert_eq!(context.set_prompt_tokens(&[2, 3], [1, 3]), Err(ErrNo::InvalidArgument));
+        assert_eq!(context.set_prompt_tokens(&[2, 3], [1, 2]), Ok(()));
+    }
+
+    #[test]
+    fn greedy_and_top_1_sampling_pick_the_best_logit() {
+        let mut context = context(Vec::new());
+        let logits = [0.1, 2.0, -1.0, 1.5];
+        context.config.temperature = 0.0;
+        assert_eq!(context.sample(&logits), 1);
+
+        context.config.temperature = 1.0;
+        context.config.top_k = Some(1);
+        assert_eq!(context.sample(&logits), 1);
+    }
+}
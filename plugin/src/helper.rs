@@ -9,3 +9,12 @@ macro_rules! get_slice {
 }
 
 pub use get_slice;
+
+/// Copies guest bytes into a vector of `T`, which may start at any alignment.
+/// Returns `None` if the length is not a multiple of the element size.
+pub fn read_pod<T: bytemuck::Pod>(bytes: &[u8]) -> Option<Vec<T>> {
+    if bytes.len() % std::mem::size_of::<T>() != 0 {
+        return None;
+    }
+    Some(bytes.chunks_exact(std::mem::size_of::<T>()).map(bytemuck::pod_read_unaligned).collect())
+}
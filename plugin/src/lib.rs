@@ -3,10 +3,28 @@
 mod backends;
 mod wasi_nn;
 mod helper;
+mod config;
+mod weights;
+mod classifier;
+mod preprocess;
 mod squeezenet;
+mod resnet;
+mod mobilenet;
+mod quantization;
+mod bert;
+mod gpt2;
+mod detection;
+mod segmentation;
+mod clip;
+mod ocr;
+mod recurrent;
+mod saliency;
+mod training;
 mod whisper;
+mod tokenizer;
+mod extension;
 
-use std::sync::Mutex;
+use std::sync::{Mutex, Once};
 use once_cell::sync::Lazy;
 use wasmedge_plugin_sdk::{
     error::CoreError,
@@ -18,9 +36,14 @@ use wasmedge_plugin_sdk::{
 use wasmedge_plugin_sdk::module::PluginModule;
 use wasmedge_plugin_sdk::types::ValType;
 use wasmedge_wasi_nn::TensorType;
-use crate::backends::get_backends;
+use cubecl_runtime::config::autotune::AutotuneLevel;
+use cubecl_runtime::config::cache::CacheConfig;
+use cubecl_runtime::config::GlobalConfig;
+use crate::config::{Autotune, PluginConfig, PLUGIN_CONFIG};
+use crate::extension::create_extension_module;
 use crate::wasi_nn::WasiNN;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrNo {
     Success = 0,              // No error occurred.
     InvalidArgument = 1,      // Caller module passed an invalid argument.
@@ -31,6 +54,9 @@ pub enum ErrNo {
     UnsupportedOperation = 6, // Unsupported Operation.
     TooLarge = 7,             // Too Large.
     NotFound = 8,             // Not Found.
+    EndOfSequence = 100,      // End of sequence found (WasmEdge extension).
+    ContextFull = 101,        // Context full (WasmEdge extension).
+    PromptTooLong = 102,      // Prompt too long (WasmEdge extension).
 }
 
 #[derive(Debug)]
@@ -43,21 +69,71 @@ struct WasiTensorData {
     tensor_length: u32,
 }
 
-pub fn create_module() -> PluginModule<()> {
-    simple_logger::SimpleLogger::new()
-        .with_level(log::LevelFilter::Error)
-        .with_module_level("wasmedge_custom_plugin", log::LevelFilter::Debug)
-        .init()
-        .expect("Failed to initialize logger");
+/// Shared by every module of the plugin, so handles from `wasi_ephemeral_nn`
+/// are valid in the extension module as well.
+pub static WASI_NN: Lazy<Mutex<WasiNN>> = Lazy::new(|| {
+    Mutex::new(WasiNN::new())
+});
+
+/// Process-wide setup; runs once no matter how many modules are instantiated.
+fn init_plugin() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Error)
+            .with_module_level("wasmedge_custom_plugin", log::LevelFilter::Debug)
+            .init()
+            .expect("Failed to initialize logger");
 
-    // debug backends
-    futures::executor::block_on(get_backends());
+        init_kernel_tuning(&PLUGIN_CONFIG);
 
-    log::info!("=== Initializing wasmedge-plugin");
+        // backends are probed lazily, on the first GPU load or report request
 
-    pub static WASI_NN: Lazy<Mutex<WasiNN>> = Lazy::new(|| {
-        Mutex::new(WasiNN::new())
+        log::info!("=== Initializing wasmedge-plugin");
     });
+}
+
+/// Applies the autotune level and points CubeCL's autotune cache at the
+/// configured directory. Runs before the first kernel launch, which reads
+/// CubeCL's global config.
+fn init_kernel_tuning(config: &PluginConfig) {
+    if !cfg!(feature = "autotune") {
+        log::info!("Autotune is disabled in this build; kernels use their default variants");
+        return;
+    }
+    if config.tune_cache_dir.is_none() && config.autotune.is_none() {
+        return;
+    }
+
+    // `set` panics once the config has been read, so build it from the
+    // defaults rather than through `GlobalConfig::get`
+    let mut kernels = GlobalConfig::default().override_from_env();
+    match &config.autotune {
+        Some(Autotune::Level(level)) => {
+            kernels.autotune.level = level.clone();
+            log::info!("Autotune level: {:?}", level);
+        }
+        Some(Autotune::Off) => {
+            // CubeCL tunes whenever the feature is compiled in; the least it can do is minimal
+            kernels.autotune.level = AutotuneLevel::Minimal;
+            log::error!("Autotune cannot be turned off at runtime in a build with the `autotune` feature; tuning at the minimal level");
+        }
+        None => {}
+    }
+    if let Some(dir) = &config.tune_cache_dir {
+        match std::fs::create_dir_all(dir) {
+            Ok(()) => {
+                kernels.autotune.cache = CacheConfig::File(dir.clone());
+                log::info!("Persisting autotune results to {}", dir.display());
+            }
+            Err(err) => log::warn!("Failed to create tune cache directory {}: {}", dir.display(), err),
+        }
+    }
+    GlobalConfig::set(kernels);
+}
+
+pub fn create_module() -> PluginModule<()> {
+    init_plugin();
 
     // define functions that will be accessible to call via the interface
     let load = |
@@ -81,11 +157,20 @@ pub fn create_module() -> PluginModule<()> {
 
     fn load_by_name<'a>(
         _inst_ref: &'a mut SyncInstanceRef,
-        _memory: &'a mut Memory,
+        memory: &'a mut Memory,
         _data: &'a mut (),
-        _args: Vec<WasmVal>,
+        args: Vec<WasmVal>,
     ) -> Result<Vec<WasmVal>, CoreError> {
-        Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)])
+        if let [WasmVal::I32(name_ptr),
+                WasmVal::I32(name_len),
+                WasmVal::I32(graph_handle_ptr)] = &args[..]
+        {
+            let mut wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.load_by_name(name_ptr, name_len, graph_handle_ptr, memory)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
     }
 
     fn load_by_name_with_config<'a>(
@@ -94,7 +179,18 @@ pub fn create_module() -> PluginModule<()> {
         _data: &'a mut (),
         args: Vec<WasmVal>,
     ) -> Result<Vec<WasmVal>, CoreError> {
-        Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)])
+        if let [WasmVal::I32(name_ptr),
+                WasmVal::I32(name_len),
+                WasmVal::I32(config_ptr),
+                WasmVal::I32(config_len),
+                WasmVal::I32(graph_handle_ptr)] = &args[..]
+        {
+            let mut wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.load_by_name_with_config(name_ptr, name_len, config_ptr, config_len, graph_handle_ptr, memory)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
     }
 
     fn init_execution_context<'a>(
@@ -153,11 +249,22 @@ pub fn create_module() -> PluginModule<()> {
 
     fn get_output_single<'a>(
         _inst_ref: &'a mut SyncInstanceRef,
-        _main_memory: &'a mut Memory,
+        memory: &'a mut Memory,
         _data: &'a mut (),
-        _args: Vec<WasmVal>,
+        args: Vec<WasmVal>,
     ) -> Result<Vec<WasmVal>, CoreError> {
-        Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)])
+        if let [WasmVal::I32(ctx_handle),
+                WasmVal::I32(output_index),
+                WasmVal::I32(output_ptr),
+                WasmVal::I32(output_max_size),
+                WasmVal::I32(output_written_len_ptr)] = &args[..]
+        {
+            let mut wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.get_output_single(ctx_handle, output_index, output_ptr, output_max_size, output_written_len_ptr, memory)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
     }
 
     fn compute<'a>(
@@ -180,27 +287,48 @@ pub fn create_module() -> PluginModule<()> {
         _inst_ref: &'a mut SyncInstanceRef,
         _main_memory: &'a mut Memory,
         _data: &'a mut (),
-        _args: Vec<WasmVal>,
+        args: Vec<WasmVal>,
     ) -> Result<Vec<WasmVal>, CoreError> {
-        Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)])
+        if let [WasmVal::I32(ctx_handle)] = &args[..]
+        {
+            let mut wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.compute_single(ctx_handle)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
     }
 
     fn fini_single<'a>(
         _inst_ref: &'a mut SyncInstanceRef,
         _main_memory: &'a mut Memory,
         _data: &'a mut (),
-        _args: Vec<WasmVal>,
+        args: Vec<WasmVal>,
     ) -> Result<Vec<WasmVal>, CoreError> {
-        Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)])
+        if let [WasmVal::I32(ctx_handle)] = &args[..]
+        {
+            let mut wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.fini_single(ctx_handle)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
     }
 
     fn unload<'a>(
         _inst_ref: &'a mut SyncInstanceRef,
         _main_memory: &'a mut Memory,
         _data: &'a mut (),
-        _args: Vec<WasmVal>,
+        args: Vec<WasmVal>,
     ) -> Result<Vec<WasmVal>, CoreError> {
-        Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)])
+        if let [WasmVal::I32(graph_handle)] = &args[..]
+        {
+            let mut wasi_nn = WASI_NN.lock().unwrap();
+            wasi_nn.unload(graph_handle)
+        }
+        else {
+            Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)])
+        }
     }
 
 
@@ -291,6 +419,7 @@ register_plugin!(
     plugin_description = "Limited wasi-nn implementation for a burn backend",
     version = (0,0,0,1),
     modules = [
-        {"wasi_nn", "Limited wasi-nn implementation for a burn backend", create_module}
+        {"wasi_nn", "Limited wasi-nn implementation for a burn backend", create_module},
+        {"wasi_nn_burn", "Host extensions of the burn wasi-nn backend", create_extension_module}
     ]
 );
\ No newline at end of file
@@ -4,5 +4,6 @@ mod backends;
 
 
 fn main() {
-    futures::executor::block_on(get_backends());
-}
\ No newline at end of file
+    let report = get_backends();
+    println!("{}", serde_json::to_string_pretty(report).expect("Failed to serialize backend report"));
+}
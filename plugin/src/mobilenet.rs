@@ -0,0 +1,40 @@
+use burn::module::{Module, Quantizer};
+use burn::prelude::Backend;
+use burn::tensor::activation::softmax;
+use burn::Tensor;
+use log::{error, info};
+use mobilenetv2_burn::model::{mobilenetv2::MobileNetV2, weights};
+
+const INPUT_DIM: usize = 4;
+const OUTPUT_DIM: usize = 2;
+
+#[derive(Clone)]
+pub struct MobileNetV2Model<B: Backend> {
+    model: MobileNetV2<B>,
+}
+
+impl<B: Backend> MobileNetV2Model<B> {
+    /// Builds MobileNetV2 with ImageNet-1k weights; the weights are downloaded
+    /// on first use and cached by `mobilenetv2-burn`.
+    pub fn new(device: &B::Device) -> Option<Self> {
+        match MobileNetV2::pretrained(weights::MobileNetV2::ImageNet1kV2, device) {
+            Ok(model) => {
+                info!("Loaded MobileNetV2 weights");
+                Some(MobileNetV2Model { model })
+            }
+            Err(err) => {
+                error!("Failed to load MobileNetV2 weights: {:?}", err);
+                None
+            }
+        }
+    }
+
+    /// MobileNetV2 returns logits; apply softmax so the output matches SqueezeNet's probabilities.
+    pub fn compute(&self, input: Tensor<B, INPUT_DIM>) -> Tensor<B, OUTPUT_DIM> {
+        softmax(self.model.forward(input), 1)
+    }
+
+    pub fn quantize(self, quantizer: &mut Quantizer) -> Self {
+        MobileNetV2Model { model: self.model.quantize_weights(quantizer) }
+    }
+}
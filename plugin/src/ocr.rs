@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::path::Path;
+use burn::module::Module;
+use burn::nn::conv::{Conv2d, Conv2dConfig};
+use burn::nn::pool::{MaxPool2d, MaxPool2dConfig};
+use burn::nn::{BiLstm, BiLstmConfig, Linear, LinearConfig, PaddingConfig2d, Relu};
+use burn::prelude::Backend;
+use burn::tensor::activation::log_softmax;
+use burn::tensor::Tensor;
+use log::{debug, error, info};
+use crate::config::{merge_config, CtcDecoder, CtcDecodingConfig, OcrConfig};
+use crate::ErrNo;
+use crate::weights::load_record;
+
+const INPUT_DIM: usize = 4;
+
+/// Height of the line images the convolutional stack reduces to a single row.
+const LINE_HEIGHT: usize = 32;
+
+/// Class index of the CTC blank.
+const BLANK: usize = 0;
+
+/// Output channels of the convolution layers; the stride pattern is fixed in `forward`.
+const CONV_CHANNELS: [usize; 7] = [64, 128, 256, 256, 512, 512, 512];
+
+/// CRNN: convolutional features per image column, a two-layer bidirectional
+/// LSTM over the columns and per-step class scores for CTC decoding.
+#[derive(Module, Debug)]
+pub struct Crnn<B: Backend> {
+    convs: Vec<Conv2d<B>>,
+    pool: MaxPool2d,
+    pool_height: MaxPool2d,
+    activation: Relu,
+    rnn1: BiLstm<B>,
+    rnn2: BiLstm<B>,
+    classifier: Linear<B>,
+}
+
+impl OcrConfig {
+    /// Number of output classes, including the blank.
+    pub fn num_classes(&self) -> usize {
+        self.charset.chars().count() + 1
+    }
+
+    pub fn init<B: Backend>(&self, device: &B::Device) -> Crnn<B> {
+        let last = CONV_CHANNELS.len() - 1;
+        let convs = CONV_CHANNELS
+            .iter()
+            .enumerate()
+            .map(|(i, &channels)| {
+                let in_channels = if i == 0 { 1 } else { CONV_CHANNELS[i - 1] };
+                // the last layer collapses the remaining two rows
+                if i == last {
+                    Conv2dConfig::new([in_channels, channels], [2, 2]).init(device)
+                } else {
+                    Conv2dConfig::new([in_channels, channels], [3, 3])
+                        .with_padding(PaddingConfig2d::Explicit(1, 1))
+                        .init(device)
+                }
+            })
+            .collect();
+
+        let features = CONV_CHANNELS[last];
+        Crnn {
+            convs,
+            pool: MaxPool2dConfig::new([2, 2]).with_strides([2, 2]).init(),
+            pool_height: MaxPool2dConfig::new([2, 1]).with_strides([2, 1]).init(),
+            activation: Relu::new(),
+            rnn1: BiLstmConfig::new(features, self.hidden_size, true).init(device),
+            rnn2: BiLstmConfig::new(2 * self.hidden_size, self.hidden_size, true).init(device),
+            classifier: LinearConfig::new(2 * self.hidden_size, self.num_classes()).init(device),
+        }
+    }
+}
+
+impl<B: Backend> Crnn<B> {
+    /// Maps `[N, 1, 32, W]` line images to `[N, W / 4 - 1, classes]` log-probabilities.
+    pub fn forward(&self, input: Tensor<B, 4>) -> Tensor<B, 3> {
+        let mut x = input;
+        for (i, conv) in self.convs.iter().enumerate() {
+            x = self.activation.forward(conv.forward(x));
+            x = match i {
+                0 | 1 => self.pool.forward(x),
+                3 | 5 => self.pool_height.forward(x),
+                _ => x,
+            };
+        }
+
+        // [N, C, 1, T] -> [N, T, C]
+        let [batch_size, channels, _, steps] = x.dims();
+        let x = x.reshape([batch_size, channels, steps]).swap_dims(1, 2);
+
+        let (x, _) = self.rnn1.forward(x, None);
+        let (x, _) = self.rnn2.forward(x, None);
+        log_softmax(self.classifier.forward(x), 2)
+    }
+}
+
+/// Recognized text of one line with the confidence of each character.
+#[derive(Debug, Clone, Default)]
+pub struct Recognition {
+    pub text: String,
+    pub confidences: Vec<f32>,
+}
+
+/// Collapses the most likely class per step. A character's confidence is its
+/// highest probability over the run of steps it was read from.
+fn greedy_decode(log_probs: &[f32], num_classes: usize) -> (Vec<usize>, Vec<f32>) {
+    let mut classes = Vec::new();
+    let mut confidences: Vec<f32> = Vec::new();
+    let mut previous = BLANK;
+
+    for step in log_probs.chunks_exact(num_classes) {
+        let (class, log_prob) = step
+            .iter()
+            .copied()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+
+        if class != BLANK {
+            if class != previous {
+                classes.push(class);
+                confidences.push(log_prob.exp());
+            } else if let Some(confidence) = confidences.last_mut() {
+                *confidence = confidence.max(log_prob.exp());
+            }
+        }
+        previous = class;
+    }
+    (classes, confidences)
+}
+
+fn log_sum_exp(a: f32, b: f32) -> f32 {
+    if a == f32::NEG_INFINITY {
+        return b;
+    }
+    if b == f32::NEG_INFINITY {
+        return a;
+    }
+    let max = a.max(b);
+    max + ((a - max).exp() + (b - max).exp()).ln()
+}
+
+/// A CTC prefix with the log-probabilities of it ending in a blank or a character.
+#[derive(Clone)]
+struct Beam {
+    blank: f32,
+    non_blank: f32,
+    confidences: Vec<f32>,
+}
+
+impl Beam {
+    fn empty() -> Self {
+        Beam { blank: f32::NEG_INFINITY, non_blank: f32::NEG_INFINITY, confidences: Vec::new() }
+    }
+
+    fn total(&self) -> f32 {
+        log_sum_exp(self.blank, self.non_blank)
+    }
+}
+
+/// CTC prefix beam search. A character's confidence is its probability at the
+/// step that first extended the prefix with it.
+fn beam_decode(log_probs: &[f32], num_classes: usize, beam_width: usize) -> (Vec<usize>, Vec<f32>) {
+    let mut beams: Vec<(Vec<usize>, Beam)> = vec![(Vec::new(), Beam { blank: 0.0, ..Beam::empty() })];
+
+    for step in log_probs.chunks_exact(num_classes) {
+        let mut next: HashMap<Vec<usize>, Beam> = HashMap::new();
+
+        for (prefix, beam) in &beams {
+            let total = beam.total();
+            for (class, &log_prob) in step.iter().enumerate() {
+                if class == BLANK {
+                    let entry = next.entry(prefix.clone()).or_insert_with(|| Beam {
+                        confidences: beam.confidences.clone(),
+                        ..Beam::empty()
+                    });
+                    entry.blank = log_sum_exp(entry.blank, total + log_prob);
+                    continue;
+                }
+
+                let mut extended = prefix.clone();
+                extended.push(class);
+                let entry = next.entry(extended).or_insert_with(|| {
+                    let mut confidences = beam.confidences.clone();
+                    confidences.push(log_prob.exp());
+                    Beam { confidences, ..Beam::empty() }
+                });
+
+                if prefix.last() == Some(&class) {
+                    // a repeated character needs a blank in between
+                    entry.non_blank = log_sum_exp(entry.non_blank, beam.blank + log_prob);
+                    let same = next.entry(prefix.clone()).or_insert_with(|| Beam {
+                        confidences: beam.confidences.clone(),
+                        ..Beam::empty()
+                    });
+                    same.non_blank = log_sum_exp(same.non_blank, beam.non_blank + log_prob);
+                } else {
+                    entry.non_blank = log_sum_exp(entry.non_blank, total + log_prob);
+                }
+            }
+        }
+
+        beams = next.into_iter().collect();
+        beams.sort_unstable_by(|a, b| b.1.total().total_cmp(&a.1.total()));
+        beams.truncate(beam_width.max(1));
+    }
+
+    beams
+        .into_iter()
+        .next()
+        .map(|(prefix, beam)| (prefix, beam.confidences))
+        .unwrap_or_default()
+}
+
+pub struct OcrModel<B: Backend> {
+    model: Crnn<B>,
+    charset: Vec<char>,
+    decoding: CtcDecodingConfig,
+    device: B::Device,
+}
+
+pub struct OcrContext<B: Backend> {
+    pub input: Option<Tensor<B, INPUT_DIM>>,
+    pub output: Option<Vec<Recognition>>,
+    decoding: CtcDecodingConfig,
+    device: B::Device,
+}
+
+impl<B: Backend> OcrModel<B> {
+    /// Builds a CRNN for the configured charset and loads the weights from a burn record file.
+    pub fn new(path: &Path, config: &OcrConfig, device: &B::Device) -> Result<Self, ErrNo> {
+        if config.charset.is_empty() || config.hidden_size == 0 {
+            error!("OCR models need a non-empty charset and hidden size");
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let record = load_record::<B, _>(path, device).map_err(|err| {
+            error!("Failed to load CRNN weights {}: {:?}", path.display(), err);
+            ErrNo::RuntimeError
+        })?;
+
+        info!("Loaded CRNN: {} classes", config.num_classes());
+
+        let model = config.init::<B>(device).load_record(record);
+        Ok(OcrModel {
+            model,
+            charset: config.charset.chars().collect(),
+            decoding: config.decoding.clone(),
+            device: device.clone(),
+        })
+    }
+
+    pub fn init_execution_context(&self) -> OcrContext<B> {
+        OcrContext {
+            input: None,
+            output: None,
+            decoding: self.decoding.clone(),
+            device: self.device.clone(),
+        }
+    }
+
+    /// Recognizes `[N, 1, 32, W]` grayscale line images; decoding runs on the host.
+    pub fn compute(&self, input: Tensor<B, INPUT_DIM>, decoding: &CtcDecodingConfig) -> Result<Vec<Recognition>, ErrNo> {
+        let [_, channels, height, width] = input.dims();
+        if channels != 1 || height != LINE_HEIGHT || width < 8 {
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let log_probs = self.model.forward(input);
+        let [batch_size, steps, num_classes] = log_probs.dims();
+        // the record's head is loaded as is, and may not match the charset
+        if num_classes != self.charset.len() + 1 {
+            error!("CRNN predicts {} classes, but the charset needs {}", num_classes, self.charset.len() + 1);
+            return Err(ErrNo::RuntimeError);
+        }
+        let log_probs = log_probs.into_data().convert::<f32>().to_vec::<f32>().map_err(|_| ErrNo::RuntimeError)?;
+
+        let recognitions = log_probs
+            .chunks_exact(steps * num_classes)
+            .map(|line| {
+                let (classes, confidences) = match decoding.decoder {
+                    CtcDecoder::Greedy => greedy_decode(line, num_classes),
+                    CtcDecoder::Beam => beam_decode(line, num_classes, decoding.beam_width),
+                };
+                let text = classes.iter().map(|&class| self.charset[class - 1]).collect();
+                Recognition { text, confidences }
+            })
+            .collect::<Vec<_>>();
+
+        debug!("Recognized {} lines over {} steps", batch_size, steps);
+        Ok(recognitions)
+    }
+}
+
+impl<B: Backend> OcrContext<B> {
+    pub fn set_input(&mut self, input: &[f32], dimens: [usize; INPUT_DIM]) {
+        let tensor = Tensor::<B, 1>::from_data(input, &self.device).reshape(dimens);
+        self.input = Some(tensor);
+    }
+
+    /// Overrides the decoder with a partial JSON object.
+    pub fn configure(&mut self, json: &str) -> Result<(), ErrNo> {
+        self.decoding = merge_config(&self.decoding, json)?;
+        Ok(())
+    }
+
+    pub fn compute(&mut self, model: &OcrModel<B>) -> Result<(), ErrNo> {
+        let input = self.input.clone().ok_or(ErrNo::InvalidArgument)?;
+        self.output = Some(model.compute(input, &self.decoding)?);
+        Ok(())
+    }
+
+    /// Output 0 is the recognized UTF-8 text, one line per image; output 1 the
+    /// `f32` confidence of every character in the same order.
+    pub fn get_output(&self, index: i32) -> Result<Vec<u8>, ErrNo> {
+        let recognitions = self.output.as_ref().ok_or(ErrNo::RuntimeError)?;
+        match index {
+            0 => {
+                let lines: Vec<&str> = recognitions.iter().map(|r| r.text.as_str()).collect();
+                Ok(lines.join("\n").into_bytes())
+            }
+            1 => {
+                let confidences: Vec<f32> = recognitions.iter().flat_map(|r| r.confidences.iter().copied()).collect();
+                Ok(bytemuck::cast_slice(&confidences).to_vec())
+            }
+            _ => Err(ErrNo::InvalidArgument),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NUM_CLASSES: usize = 4;
+
+    /// Log-probabilities of steps that each favour one class with probability 0.9.
+    fn peaked(classes: &[usize]) -> Vec<f32> {
+        let other = (0.1 / (NUM_CLASSES - 1) as f32).ln();
+        classes
+            .iter()
+            .flat_map(|&class| (0..NUM_CLASSES).map(move |c| if c == class { 0.9f32.ln() } else { other }))
+            .collect()
+    }
+
+    #[test]
+    fn greedy_keeps_repeats_split_by_a_blank() {
+        let (classes, confidences) = greedy_decode(&peaked(&[1, BLANK, 1]), NUM_CLASSES);
+        assert_eq!(classes, vec![1, 1]);
+        assert_eq!(confidences.len(), 2);
+    }
+
+    #[test]
+    fn greedy_merges_repeats() {
+        let (classes, confidences) = greedy_decode(&peaked(&[1, 1, 1, 2, 2, BLANK]), NUM_CLASSES);
+        assert_eq!(classes, vec![1, 2]);
+        assert!(confidences.iter().all(|&confidence| (confidence - 0.9).abs() < 1e-5));
+    }
+
+    #[test]
+    fn beam_keeps_repeats_split_by_a_blank() {
+        let (classes, _) = beam_decode(&peaked(&[1, BLANK, 1]), NUM_CLASSES, 8);
+        assert_eq!(classes, vec![1, 1]);
+        let (classes, _) = beam_decode(&peaked(&[1, 1, 1, 2, 2]), NUM_CLASSES, 8);
+        assert_eq!(classes, vec![1, 2]);
+    }
+
+    #[test]
+    fn beam_of_width_one_matches_greedy() {
+        let steps = [BLANK, 3, 3, BLANK, 3, 1, 2, 2, BLANK, BLANK, 1];
+        let log_probs = peaked(&steps);
+        let (greedy, _) = greedy_decode(&log_probs, NUM_CLASSES);
+        let (beam, _) = beam_decode(&log_probs, NUM_CLASSES, 1);
+        assert_eq!(beam, greedy);
+        assert_eq!(beam, vec![3, 3, 1, 2, 1]);
+    }
+
+    #[test]
+    fn empty_input_decodes_to_nothing() {
+        assert!(greedy_decode(&[], NUM_CLASSES).0.is_empty());
+        assert!(beam_decode(&[], NUM_CLASSES, 4).0.is_empty());
+    }
+}
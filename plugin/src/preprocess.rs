@@ -0,0 +1,137 @@
+use std::io::Cursor;
+use image::imageops::{self, FilterType};
+use image::{ImageReader, Limits, RgbImage};
+use log::{debug, error};
+use crate::ErrNo;
+
+/// Side of the square crop the ImageNet classifiers expect.
+const CROP_SIZE: u32 = 224;
+
+/// Shorter side an image is resized to before the center crop.
+const RESIZE_SIZE: u32 = 256;
+
+/// Largest side of a decoded or resized image, which bounds the host memory a
+/// few bytes of encoded input can claim; resizing allows aspect ratios up to 32:1.
+const MAX_IMAGE_SIDE: u32 = 8192;
+
+const IMAGENET_MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+const IMAGENET_STD: [f32; 3] = [0.229, 0.224, 0.225];
+
+/// Reads a `U8` classifier input: `[H, W, 3]` is a raw RGB array, any other
+/// shape holds the bytes of an encoded JPEG or PNG.
+pub fn read_image(bytes: &[u8], dimensions: &[usize]) -> Result<RgbImage, ErrNo> {
+    if let [height, width, 3] = *dimensions {
+        return RgbImage::from_raw(width as u32, height as u32, bytes.to_vec()).ok_or(ErrNo::InvalidArgument);
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_SIDE);
+    limits.max_image_height = Some(MAX_IMAGE_SIDE);
+
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format().map_err(|_| ErrNo::InvalidArgument)?;
+    reader.limits(limits);
+    reader
+        .decode()
+        .map(|image| image.to_rgb8())
+        .map_err(|err| {
+            error!("Failed to decode input image: {}", err);
+            ErrNo::InvalidArgument
+        })
+}
+
+/// Standard ImageNet evaluation transform: resize the shorter side to 256,
+/// center-crop 224x224 and normalize by the ImageNet mean and std. Returns the
+/// `[1, 3, 224, 224]` NCHW data.
+pub fn imagenet_tensor(image: &RgbImage) -> Result<(Vec<f32>, [usize; 4]), ErrNo> {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return Err(ErrNo::InvalidArgument);
+    }
+
+    let scale = RESIZE_SIZE as f32 / width.min(height) as f32;
+    let resized_width = ((width as f32 * scale).round() as u32).max(CROP_SIZE);
+    let resized_height = ((height as f32 * scale).round() as u32).max(CROP_SIZE);
+    if width.max(height) > MAX_IMAGE_SIDE || resized_width.max(resized_height) > MAX_IMAGE_SIDE {
+        error!("{}x{} image exceeds the {} pixel side limit when resized", width, height, MAX_IMAGE_SIDE);
+        return Err(ErrNo::InvalidArgument);
+    }
+    let resized = imageops::resize(image, resized_width, resized_height, FilterType::Triangle);
+
+    let left = (resized_width - CROP_SIZE) / 2;
+    let top = (resized_height - CROP_SIZE) / 2;
+    let cropped = imageops::crop_imm(&resized, left, top, CROP_SIZE, CROP_SIZE).to_image();
+
+    let plane = (CROP_SIZE * CROP_SIZE) as usize;
+    let mut data = vec![0f32; 3 * plane];
+    for (i, pixel) in cropped.pixels().enumerate() {
+        for channel in 0..3 {
+            let value = pixel.0[channel] as f32 / 255.0;
+            data[channel * plane + i] = (value - IMAGENET_MEAN[channel]) / IMAGENET_STD[channel];
+        }
+    }
+
+    debug!("Preprocessed {}x{} image to {}x{}", width, height, CROP_SIZE, CROP_SIZE);
+    Ok((data, [1, 3, CROP_SIZE as usize, CROP_SIZE as usize]))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageFormat, Rgb};
+    use super::*;
+
+    const COLOR: [u8; 3] = [255, 0, 128];
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        RgbImage::from_pixel(width, height, Rgb(COLOR))
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn encoded_image_is_cropped_and_normalized() {
+        let image = read_image(&png(40, 30), &[]).unwrap();
+        assert_eq!(image.dimensions(), (40, 30));
+
+        let (data, dimensions) = imagenet_tensor(&image).unwrap();
+        assert_eq!(dimensions, [1, 3, 224, 224]);
+        assert_eq!(data.len(), 3 * 224 * 224);
+
+        // a uniform image stays uniform through resize and crop
+        let plane = 224 * 224;
+        for channel in 0..3 {
+            let expected = (COLOR[channel] as f32 / 255.0 - IMAGENET_MEAN[channel]) / IMAGENET_STD[channel];
+            let values = &data[channel * plane..(channel + 1) * plane];
+            assert!(values.iter().all(|&value| (value - expected).abs() < 1e-5), "channel {}", channel);
+        }
+    }
+
+    #[test]
+    fn raw_rgb_input_keeps_its_pixels() {
+        let bytes: Vec<u8> = COLOR.repeat(6);
+        let image = read_image(&bytes, &[2, 3, 3]).unwrap();
+        assert_eq!(image.dimensions(), (3, 2));
+        assert_eq!(image.get_pixel(2, 1).0, COLOR);
+    }
+
+    #[test]
+    fn corrupt_bytes_are_rejected() {
+        let mut bytes = png(8, 8);
+        bytes.truncate(bytes.len() / 2);
+        assert_eq!(read_image(&bytes, &[]).unwrap_err(), ErrNo::InvalidArgument);
+        assert_eq!(read_image(b"not an image", &[12]).unwrap_err(), ErrNo::InvalidArgument);
+        assert_eq!(read_image(&[0; 5], &[2, 3, 3]).unwrap_err(), ErrNo::InvalidArgument);
+    }
+
+    #[test]
+    fn oversized_images_are_rejected() {
+        // a thin strip decodes cheaply but would resize to 256x5120000
+        let strip = read_image(&png(1, 20000), &[]);
+        assert_eq!(strip.unwrap_err(), ErrNo::InvalidArgument);
+
+        let strip = RgbImage::new(1, 2000);
+        assert_eq!(imagenet_tensor(&strip).unwrap_err(), ErrNo::InvalidArgument);
+        assert!(imagenet_tensor(&RgbImage::new(32, 1000)).is_ok());
+    }
+}
@@ -0,0 +1,307 @@
+use std::time::Instant;
+use burn::module::{Module, Param, Quantizer};
+use burn::nn::conv::Conv2d;
+use burn::prelude::Backend;
+use burn::tensor::ops::QuantizedTensor;
+use burn::tensor::quantization::{
+    Calibration, CalibrationRange, QTensorPrimitive, QuantLevel, QuantParam, QuantScheme, QuantValue,
+    QuantizationParameters,
+};
+use burn::tensor::TensorData;
+use burn::Tensor;
+use log::{error, info, warn};
+use crate::classifier::ClassifierModel;
+use crate::config::{QuantGranularity, QuantizationConfig};
+use crate::preprocess::{imagenet_tensor, read_image};
+use crate::ErrNo;
+
+/// Largest magnitude of a symmetric int8 value.
+const INT8_MAX: f32 = 127.0;
+
+/// Floor of the ranges scales are derived from, so an all-zero channel or
+/// activation never divides by zero.
+const MIN_RANGE: f32 = 1e-6;
+
+/// Symmetric int8 with one `f32` scale per tensor. Channel granularity stores
+/// weights with it too, after dividing every output channel by its own range.
+fn int8_scheme<B: Backend>() -> QuantScheme {
+    <QuantizedTensor<B> as QTensorPrimitive>::default_scheme()
+        .with_value(QuantValue::Q8S)
+        .with_level(QuantLevel::Tensor)
+        .with_param(QuantParam::F32)
+}
+
+/// Bytes of a tensor as stored by its backend, int8 values and scales included.
+pub(crate) fn stored_bytes<B: Backend, const D: usize>(tensor: Tensor<B, D>) -> usize {
+    tensor.into_data().as_bytes().len()
+}
+
+fn per_channel<B: Backend>(values: Tensor<B, 1>) -> Tensor<B, 4> {
+    let [channels] = values.dims();
+    values.reshape([1, channels, 1, 1])
+}
+
+/// A convolution with an int8 weight and a floating-point bias.
+///
+/// At channel granularity the weight is stored divided by the largest magnitude
+/// of each output channel, so every channel spans the whole int8 range on the
+/// shared tensor scale, and the output is multiplied back by those magnitudes:
+/// an effective scale of `magnitude / 127` per output channel.
+#[derive(Module, Debug)]
+pub struct QuantizedConv2d<B: Backend> {
+    /// Holds the int8 weight; its bias is moved out to apply after the channel scales.
+    conv: Conv2d<B>,
+    /// `[out_channels]` largest weight magnitudes, at channel granularity.
+    channel_scales: Option<Param<Tensor<B, 1>>>,
+    bias: Option<Param<Tensor<B, 1>>>,
+    /// Scale of the int8 input from calibration; without one the input stays
+    /// in floating point.
+    input_scale: Option<Param<Tensor<B, 1>>>,
+}
+
+impl<B: Backend> QuantizedConv2d<B> {
+    pub fn new(mut conv: Conv2d<B>, granularity: QuantGranularity, input_range: Option<CalibrationRange<B>>) -> Self {
+        let scheme = int8_scheme::<B>();
+        let bias = conv.bias.take();
+
+        let [channels, inputs, kernel_height, kernel_width] = conv.weight.dims();
+        let channel_scales = (granularity == QuantGranularity::Channel).then(|| {
+            conv.weight
+                .val()
+                .abs()
+                .reshape([channels, inputs * kernel_height * kernel_width])
+                .max_dim(1)
+                .reshape([channels])
+                .clamp_min(MIN_RANGE)
+        });
+        conv.weight = conv.weight.map(|weight| {
+            let weight = match &channel_scales {
+                Some(scales) => weight / scales.clone().reshape([channels, 1, 1, 1]),
+                None => weight,
+            };
+            weight.quantize_dynamic(&scheme)
+        });
+
+        // symmetric, so the larger side of the observed range sets the scale
+        let input_scale = input_range.map(|range| {
+            range.min.abs().max_pair(range.max.abs()).clamp_min(MIN_RANGE).div_scalar(INT8_MAX)
+        });
+
+        QuantizedConv2d {
+            conv,
+            channel_scales: channel_scales.map(Param::from_tensor),
+            bias,
+            input_scale: input_scale.map(Param::from_tensor),
+        }
+    }
+
+    pub fn forward(&self, input: Tensor<B, 4>) -> Tensor<B, 4> {
+        let input = match &self.input_scale {
+            Some(scale) => input
+                .quantize(&int8_scheme::<B>(), QuantizationParameters { scales: scale.val() })
+                .dequantize(),
+            None => input,
+        };
+
+        let output = self.conv.forward(input);
+        let output = match &self.channel_scales {
+            Some(scales) => output * per_channel(scales.val()),
+            None => output,
+        };
+        match &self.bias {
+            Some(bias) => output + per_channel(bias.val()),
+            None => output,
+        }
+    }
+
+    /// Bytes of the weight, scales and bias as stored on the device.
+    pub fn weight_bytes(&self) -> usize {
+        let param = |param: &Option<Param<Tensor<B, 1>>>| param.as_ref().map_or(0, |param| stored_bytes(param.val()));
+        stored_bytes(self.conv.weight.val()) + param(&self.channel_scales) + param(&self.bias) + param(&self.input_scale)
+    }
+}
+
+/// Decodes the calibration samples: encoded JPEG or PNG images, preprocessed
+/// like a `U8` classifier input.
+fn calibration_inputs<B: Backend>(samples: &[Vec<u8>], device: &B::Device) -> Result<Vec<Tensor<B, 4>>, ErrNo> {
+    if samples.is_empty() {
+        error!("Calibration needs sample images passed as load builders after the config");
+        return Err(ErrNo::InvalidArgument);
+    }
+    samples
+        .iter()
+        .map(|bytes| {
+            let (data, dimensions) = imagenet_tensor(&read_image(bytes, &[])?)?;
+            Ok(Tensor::from_data(TensorData::new(data, dimensions), device))
+        })
+        .collect()
+}
+
+/// Quantizes the weights of a classifier to int8, and with `calibrate` the
+/// convolution inputs too. Channel scales and calibration need the layer
+/// layout, so they are only available for SqueezeNet.
+pub fn quantize_classifier<B: Backend>(
+    model: ClassifierModel<B>,
+    config: &QuantizationConfig,
+    samples: &[Vec<u8>],
+    device: &B::Device,
+) -> Result<ClassifierModel<B>, ErrNo> {
+    let start = Instant::now();
+    if !config.calibrate && !samples.is_empty() {
+        warn!("Ignoring {} calibration samples, calibration is not enabled", samples.len());
+    }
+    let quantizer = || Quantizer { calibration: Calibration::MinMax, scheme: int8_scheme::<B>() };
+
+    let model = match model {
+        ClassifierModel::Squeezenet(model) => {
+            let inputs = if config.calibrate { calibration_inputs(samples, device)? } else { Vec::new() };
+            let float_bytes = model.weight_bytes();
+            let model = model.quantize(config.granularity, &inputs);
+            info!("Quantized SqueezeNet weights from {} to {} bytes", float_bytes, model.weight_bytes());
+            ClassifierModel::Squeezenet(model)
+        }
+        _ if config.granularity == QuantGranularity::Channel || config.calibrate => {
+            warn!("Channel scales and calibration are only supported for SqueezeNet");
+            return Err(ErrNo::UnsupportedOperation);
+        }
+        ClassifierModel::Resnet(model) => ClassifierModel::Resnet(model.quantize(&mut quantizer())),
+        ClassifierModel::MobileNetV2(model) => ClassifierModel::MobileNetV2(model.quantize(&mut quantizer())),
+    };
+
+    info!(
+        "Quantized to int8 per {:?}{} in {:?}",
+        config.granularity,
+        if config.calibrate { " with calibrated inputs" } else { "" },
+        start.elapsed(),
+    );
+    Ok(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::ops::Range;
+    use burn::backend::NdArray;
+    use image::{ImageFormat, Rgb, RgbImage};
+    use crate::squeezenet::SqueezenetModel;
+    use super::*;
+
+    type TestBackend = NdArray<f32>;
+
+    /// Largest difference allowed between a float and an int8 class probability.
+    const MAX_PROBABILITY_ERROR: f32 = 0.05;
+
+    /// Number of predictions of the int8 model the float top-1 class must be among.
+    const TOP_K: usize = 5;
+
+    /// A fixed 320x240 PNG of stripes, rings and gradients, different for every `variant`.
+    fn scene(variant: u32) -> Vec<u8> {
+        let image = RgbImage::from_fn(320, 240, |x, y| {
+            let stripes = ((x + 7 * variant) / (8 + 4 * variant) + y / 16) % 2;
+            let radius = (x as f32 - 160.0).hypot(y as f32 - 120.0);
+            let rings = (radius / (10.0 + 5.0 * variant as f32)) as u32 % 2;
+            Rgb([
+                (x * 255 / 320) as u8,
+                if stripes == 1 { 200 } else { 40 },
+                if rings == 1 { 220 } else { (y * 255 / 240) as u8 },
+            ])
+        });
+        let mut bytes = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).unwrap();
+        bytes
+    }
+
+    /// The scenes batched through the `U8` classifier preprocessing.
+    fn scenes(variants: Range<u32>) -> Tensor<TestBackend, 4> {
+        let samples: Vec<_> = variants.map(scene).collect();
+        Tensor::cat(calibration_inputs(&samples, &Default::default()).unwrap(), 0)
+    }
+
+    fn values(tensor: Tensor<TestBackend, 2>) -> Vec<f32> {
+        tensor.into_data().convert::<f32>().to_vec().unwrap()
+    }
+
+    fn float_model() -> SqueezenetModel<TestBackend> {
+        SqueezenetModel::new(true, &Default::default())
+    }
+
+    fn quantized_model(config: &QuantizationConfig, samples: &[Vec<u8>]) -> SqueezenetModel<TestBackend> {
+        let model = ClassifierModel::Squeezenet(float_model());
+        match quantize_classifier(model, config, samples, &Default::default()).unwrap() {
+            ClassifierModel::Squeezenet(model) => model,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Compares the class probabilities of the int8 and float models on scenes
+    /// the calibration did not see.
+    fn assert_close_to_float(config: QuantizationConfig) {
+        let calibration: Vec<_> = (0..2).map(scene).collect();
+        let float = float_model();
+        let quantized = quantized_model(&config, &calibration);
+
+        let input = scenes(2..6);
+        let (_, expected) = float.compute(input.clone());
+        let (_, actual) = quantized.compute(input);
+        let [_, classes] = expected.dims();
+
+        for (expected, actual) in values(expected).chunks_exact(classes).zip(values(actual).chunks_exact(classes)) {
+            let error = expected.iter().zip(actual).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+            assert!(error < MAX_PROBABILITY_ERROR, "probability error {} with {:?}", error, config);
+
+            let top1 = (0..classes).max_by(|&a, &b| expected[a].total_cmp(&expected[b])).unwrap();
+            let rank = actual.iter().filter(|&&probability| probability > actual[top1]).count();
+            assert!(rank < TOP_K, "float top-1 class ranks {} with {:?}", rank + 1, config);
+        }
+    }
+
+    #[test]
+    fn tensor_quantization_matches_float_model() {
+        assert_close_to_float(QuantizationConfig { granularity: QuantGranularity::Tensor, calibrate: false });
+    }
+
+    #[test]
+    fn channel_quantization_matches_float_model() {
+        assert_close_to_float(QuantizationConfig { granularity: QuantGranularity::Channel, calibrate: false });
+    }
+
+    #[test]
+    fn calibrated_quantization_matches_float_model() {
+        assert_close_to_float(QuantizationConfig { granularity: QuantGranularity::Channel, calibrate: true });
+    }
+
+    #[test]
+    fn calibration_needs_samples() {
+        let model = ClassifierModel::Squeezenet(float_model());
+        let config = QuantizationConfig { granularity: QuantGranularity::Tensor, calibrate: true };
+        let err = quantize_classifier(model, &config, &[], &Default::default()).err();
+        assert_eq!(err, Some(ErrNo::InvalidArgument));
+    }
+
+    /// Times a few batches on NdArray; the int8 weights must take under a third
+    /// of the float bytes, the latencies are printed for comparison.
+    #[test]
+    fn quantization_shrinks_weights() {
+        const PASSES: u32 = 3;
+        let float = float_model();
+        let quantized = quantized_model(&QuantizationConfig { granularity: QuantGranularity::Channel, calibrate: false }, &[]);
+        let input = scenes(0..4);
+
+        let latency = |model: &SqueezenetModel<TestBackend>| {
+            let start = Instant::now();
+            for _ in 0..PASSES {
+                let (_, probabilities) = model.compute(input.clone());
+                probabilities.into_data();
+            }
+            start.elapsed() / PASSES
+        };
+        let (float_latency, quantized_latency) = (latency(&float), latency(&quantized));
+        let (float_bytes, quantized_bytes) = (float.weight_bytes(), quantized.weight_bytes());
+        println!(
+            "SqueezeNet on NdArray: float {} bytes, {:?} per batch; int8 {} bytes, {:?} per batch",
+            float_bytes, float_latency, quantized_bytes, quantized_latency,
+        );
+
+        assert!(quantized_bytes * 3 < float_bytes, "int8 weights take {} of {} bytes", quantized_bytes, float_bytes);
+    }
+}
@@ -0,0 +1,283 @@
+use std::path::Path;
+use burn::module::Module;
+use burn::nn::gru::{Gru, GruConfig};
+use burn::nn::{Linear, LinearConfig, Lstm, LstmConfig, LstmState};
+use burn::prelude::Backend;
+use burn::tensor::Tensor;
+use log::{error, info};
+use crate::config::{RecurrentCell, RecurrentConfig};
+use crate::ErrNo;
+use crate::weights::load_record;
+
+const SEQUENCE_DIM: usize = 3;
+
+/// Input and output indices of a recurrent context: the timestep window and
+/// its predictions, then the carried state.
+const SEQUENCE_INDEX: i32 = 0;
+const HIDDEN_INDEX: i32 = 1;
+const CELL_INDEX: i32 = 2;
+
+#[derive(Module, Debug)]
+pub struct LstmForecaster<B: Backend> {
+    layers: Vec<Lstm<B>>,
+    head: Linear<B>,
+}
+
+#[derive(Module, Debug)]
+pub struct GruForecaster<B: Backend> {
+    layers: Vec<Gru<B>>,
+    head: Linear<B>,
+}
+
+pub enum Forecaster<B: Backend> {
+    Lstm(LstmForecaster<B>),
+    Gru(GruForecaster<B>),
+}
+
+/// Per-layer state `[layers, batch, hidden]`; only LSTMs have a cell state.
+#[derive(Debug, Clone)]
+pub struct RecurrentState<B: Backend> {
+    pub hidden: Tensor<B, SEQUENCE_DIM>,
+    pub cell: Option<Tensor<B, SEQUENCE_DIM>>,
+}
+
+impl RecurrentConfig {
+    fn layer_input_size(&self, layer: usize) -> usize {
+        if layer == 0 { self.input_size } else { self.hidden_size }
+    }
+
+    pub fn init_lstm<B: Backend>(&self, device: &B::Device) -> LstmForecaster<B> {
+        LstmForecaster {
+            layers: (0..self.num_layers)
+                .map(|layer| LstmConfig::new(self.layer_input_size(layer), self.hidden_size, true).init(device))
+                .collect(),
+            head: LinearConfig::new(self.hidden_size, self.output_size).init(device),
+        }
+    }
+
+    pub fn init_gru<B: Backend>(&self, device: &B::Device) -> GruForecaster<B> {
+        GruForecaster {
+            layers: (0..self.num_layers)
+                .map(|layer| GruConfig::new(self.layer_input_size(layer), self.hidden_size, true).init(device))
+                .collect(),
+            head: LinearConfig::new(self.hidden_size, self.output_size).init(device),
+        }
+    }
+}
+
+/// Splits `[layers, batch, hidden]` into one `[batch, hidden]` tensor per layer.
+fn per_layer<B: Backend>(state: Tensor<B, SEQUENCE_DIM>) -> Vec<Tensor<B, 2>> {
+    let [_, batch_size, hidden_size] = state.dims();
+    state.iter_dim(0).map(|layer| layer.reshape([batch_size, hidden_size])).collect()
+}
+
+impl<B: Backend> Forecaster<B> {
+    /// Runs `[batch, seq, input]` through the stack starting from `state` and
+    /// returns the `[batch, seq, output]` predictions with the final state.
+    pub fn forward(&self, input: Tensor<B, SEQUENCE_DIM>, state: RecurrentState<B>) -> (Tensor<B, SEQUENCE_DIM>, RecurrentState<B>) {
+        match self {
+            Forecaster::Lstm(model) => {
+                let hidden = per_layer(state.hidden);
+                let cell = per_layer(state.cell.expect("LSTM state without cell"));
+
+                let mut x = input;
+                let mut next_hidden = Vec::with_capacity(model.layers.len());
+                let mut next_cell = Vec::with_capacity(model.layers.len());
+                for ((layer, hidden), cell) in model.layers.iter().zip(hidden).zip(cell) {
+                    let (output, state) = layer.forward(x, Some(LstmState::new(cell, hidden)));
+                    x = output;
+                    next_hidden.push(state.hidden);
+                    next_cell.push(state.cell);
+                }
+
+                let state = RecurrentState {
+                    hidden: Tensor::stack(next_hidden, 0),
+                    cell: Some(Tensor::stack(next_cell, 0)),
+                };
+                (model.head.forward(x), state)
+            }
+            Forecaster::Gru(model) => {
+                let hidden = per_layer(state.hidden);
+
+                let mut x = input;
+                let mut next_hidden = Vec::with_capacity(model.layers.len());
+                for (layer, hidden) in model.layers.iter().zip(hidden) {
+                    x = layer.forward(x, Some(hidden));
+                    // the last timestep's output is the layer's final hidden state
+                    let [batch_size, seq_length, hidden_size] = x.dims();
+                    next_hidden.push(
+                        x.clone()
+                            .slice([0..batch_size, seq_length - 1..seq_length, 0..hidden_size])
+                            .reshape([batch_size, hidden_size]),
+                    );
+                }
+
+                let state = RecurrentState { hidden: Tensor::stack(next_hidden, 0), cell: None };
+                (model.head.forward(x), state)
+            }
+        }
+    }
+}
+
+pub struct RecurrentModel<B: Backend> {
+    model: Forecaster<B>,
+    config: RecurrentConfig,
+    device: B::Device,
+}
+
+pub struct RecurrentContext<B: Backend> {
+    pub input: Option<Tensor<B, SEQUENCE_DIM>>,
+    pub output: Option<Tensor<B, SEQUENCE_DIM>>,
+    /// Carried over from one `compute` to the next until reset or overwritten.
+    pub state: Option<RecurrentState<B>>,
+    config: RecurrentConfig,
+    device: B::Device,
+}
+
+impl<B: Backend> RecurrentModel<B> {
+    /// Builds the configured LSTM or GRU stack and loads the weights from a burn record file.
+    pub fn new(path: &Path, config: &RecurrentConfig, device: &B::Device) -> Result<Self, ErrNo> {
+        if config.num_layers == 0 {
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let model = match config.cell {
+            RecurrentCell::Lstm => load_record::<B, _>(path, device)
+                .map(|record| Forecaster::Lstm(config.init_lstm::<B>(device).load_record(record))),
+            RecurrentCell::Gru => load_record::<B, _>(path, device)
+                .map(|record| Forecaster::Gru(config.init_gru::<B>(device).load_record(record))),
+        };
+        let model = model.map_err(|err| {
+            error!("Failed to load {:?} weights {}: {:?}", config.cell, path.display(), err);
+            ErrNo::RuntimeError
+        })?;
+
+        info!(
+            "Loaded {:?} forecaster: {} layers of {} units",
+            config.cell, config.num_layers, config.hidden_size
+        );
+
+        Ok(RecurrentModel { model, config: config.clone(), device: device.clone() })
+    }
+
+    pub fn init_execution_context(&self) -> RecurrentContext<B> {
+        RecurrentContext {
+            input: None,
+            output: None,
+            state: None,
+            config: self.config.clone(),
+            device: self.device.clone(),
+        }
+    }
+}
+
+impl<B: Backend> RecurrentContext<B> {
+    fn zero_state(&self, batch_size: usize) -> RecurrentState<B> {
+        let zeros = || Tensor::zeros([self.config.num_layers, batch_size, self.config.hidden_size], &self.device);
+        RecurrentState {
+            hidden: zeros(),
+            cell: (self.config.cell == RecurrentCell::Lstm).then(zeros),
+        }
+    }
+
+    /// Index 0 takes a `[batch, seq, input]` window; 1 and 2 overwrite the hidden
+    /// and cell state with `[layers, batch, hidden]` tensors.
+    pub fn set_input(&mut self, index: i32, input: &[f32], dimens: &[usize]) -> Result<(), ErrNo> {
+        let dimens: [usize; SEQUENCE_DIM] = dimens.try_into().map_err(|_| ErrNo::InvalidArgument)?;
+        let tensor = Tensor::<B, 1>::from_data(input, &self.device).reshape(dimens);
+
+        match index {
+            SEQUENCE_INDEX => {
+                if dimens[1] == 0 || dimens[2] != self.config.input_size {
+                    return Err(ErrNo::InvalidArgument);
+                }
+                self.input = Some(tensor);
+            }
+            HIDDEN_INDEX | CELL_INDEX => {
+                let [layers, batch_size, hidden_size] = dimens;
+                if layers != self.config.num_layers || hidden_size != self.config.hidden_size {
+                    return Err(ErrNo::InvalidArgument);
+                }
+                if index == CELL_INDEX && self.config.cell != RecurrentCell::Lstm {
+                    return Err(ErrNo::UnsupportedOperation);
+                }
+
+                let mut state = match self.state.take() {
+                    Some(state) if state.hidden.dims()[1] == batch_size => state,
+                    _ => self.zero_state(batch_size),
+                };
+                if index == HIDDEN_INDEX {
+                    state.hidden = tensor;
+                } else {
+                    state.cell = Some(tensor);
+                }
+                self.state = Some(state);
+            }
+            _ => return Err(ErrNo::InvalidArgument),
+        }
+        Ok(())
+    }
+
+    pub fn compute(&mut self, model: &RecurrentModel<B>) -> Result<(), ErrNo> {
+        let input = self.input.clone().ok_or(ErrNo::InvalidArgument)?;
+        let [batch_size, _, _] = input.dims();
+
+        // a mismatched batch keeps the carried state for a corrected input
+        if self.state.as_ref().is_some_and(|state| state.hidden.dims()[1] != batch_size) {
+            return Err(ErrNo::InvalidArgument);
+        }
+        let state = match self.state.take() {
+            Some(state) => state,
+            None => self.zero_state(batch_size),
+        };
+
+        let (output, state) = model.model.forward(input, state);
+        self.output = Some(output);
+        self.state = Some(state);
+        Ok(())
+    }
+
+    /// Forgets the carried state; the next `compute` starts from zeros.
+    pub fn reset(&mut self) {
+        self.state = None;
+    }
+
+    /// Returns the dimensions `get_output` would return for the same index.
+    pub fn output_shape(&self, index: i32) -> Result<Vec<usize>, ErrNo> {
+        let dims = match index {
+            SEQUENCE_INDEX => self.output.as_ref().map(|output| output.dims().to_vec()),
+            HIDDEN_INDEX => self.state.as_ref().map(|state| state.hidden.dims().to_vec()),
+            CELL_INDEX => {
+                if self.config.cell != RecurrentCell::Lstm {
+                    return Err(ErrNo::UnsupportedOperation);
+                }
+                self.state.as_ref().and_then(|state| state.cell.as_ref()).map(|cell| cell.dims().to_vec())
+            }
+            _ => return Err(ErrNo::InvalidArgument),
+        };
+        dims.ok_or(ErrNo::RuntimeError)
+    }
+
+    /// Output 0 holds the `[batch, seq, output]` predictions; 1 and 2 the current
+    /// hidden and cell state as `[layers, batch, hidden]`.
+    pub fn get_output(&self, index: i32) -> Result<Vec<u8>, ErrNo> {
+        let tensor = match index {
+            SEQUENCE_INDEX => self.output.clone(),
+            HIDDEN_INDEX => self.state.as_ref().map(|state| state.hidden.clone()),
+            CELL_INDEX => {
+                if self.config.cell != RecurrentCell::Lstm {
+                    return Err(ErrNo::UnsupportedOperation);
+                }
+                self.state.as_ref().and_then(|state| state.cell.clone())
+            }
+            _ => return Err(ErrNo::InvalidArgument),
+        };
+
+        let output = tensor.ok_or(ErrNo::RuntimeError)?
+            .into_data()
+            .convert::<f32>()
+            .to_vec::<f32>()
+            .map_err(|_| ErrNo::RuntimeError)?;
+        Ok(bytemuck::cast_slice(&output).to_vec())
+    }
+}
@@ -0,0 +1,47 @@
+use burn::module::{Module, Quantizer};
+use burn::prelude::Backend;
+use burn::tensor::activation::softmax;
+use burn::Tensor;
+use log::{error, info};
+use resnet_burn::{weights, ResNet};
+use crate::config::ModelKind;
+
+const INPUT_DIM: usize = 4;
+const OUTPUT_DIM: usize = 2;
+
+#[derive(Clone)]
+pub struct ResnetModel<B: Backend> {
+    model: ResNet<B>,
+}
+
+impl<B: Backend> ResnetModel<B> {
+    /// Builds a ResNet with ImageNet-1k weights; the weights are downloaded
+    /// on first use and cached by `resnet-burn`.
+    pub fn new(kind: ModelKind, device: &B::Device) -> Option<Self> {
+        let model = match kind {
+            ModelKind::Resnet18 => ResNet::resnet18_pretrained(weights::ResNet18::ImageNet1kV1, device),
+            ModelKind::Resnet50 => ResNet::resnet50_pretrained(weights::ResNet50::ImageNet1kV2, device),
+            _ => return None,
+        };
+
+        match model {
+            Ok(model) => {
+                info!("Loaded {:?} weights", kind);
+                Some(ResnetModel { model })
+            }
+            Err(err) => {
+                error!("Failed to load {:?} weights: {:?}", kind, err);
+                None
+            }
+        }
+    }
+
+    /// ResNet returns logits; apply softmax so the output matches SqueezeNet's probabilities.
+    pub fn compute(&self, input: Tensor<B, INPUT_DIM>) -> Tensor<B, OUTPUT_DIM> {
+        softmax(self.model.forward(input), 1)
+    }
+
+    pub fn quantize(self, quantizer: &mut Quantizer) -> Self {
+        ResnetModel { model: self.model.quantize_weights(quantizer) }
+    }
+}
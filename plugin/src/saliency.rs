@@ -0,0 +1,49 @@
+use burn::backend::Autodiff;
+use burn::prelude::Backend;
+use burn::tensor::{Int, Tensor};
+use crate::config::{SaliencyConfig, SaliencyMethod};
+use crate::squeezenet::SqueezeNet;
+
+const INPUT_DIM: usize = 4;
+
+/// Gradient of the summed `[N, 1]` class logits with respect to `input`.
+fn input_gradient<B: Backend>(
+    model: &SqueezeNet<Autodiff<B>>,
+    input: Tensor<B, INPUT_DIM>,
+    classes: Tensor<B, 2, Int>,
+) -> Tensor<B, INPUT_DIM> {
+    let input = Tensor::<Autodiff<B>, INPUT_DIM>::from_inner(input).require_grad();
+    let (_, logits) = model.forward_logits(input.clone());
+    let score = logits.gather(1, Tensor::from_inner(classes)).sum();
+
+    let grads = score.backward();
+    input.grad(&grads).expect("the input requires its gradient")
+}
+
+/// Computes `[N, H, W]` saliency maps of `input` for the `[N, 1]` classes: the
+/// largest absolute attribution over the channels of each pixel, scaled to
+/// `[0, 1]` per image.
+pub fn saliency_map<B: Backend>(
+    model: &SqueezeNet<Autodiff<B>>,
+    input: Tensor<B, INPUT_DIM>,
+    classes: Tensor<B, 2, Int>,
+    config: &SaliencyConfig,
+) -> Tensor<B, 3> {
+    let attribution = match config.method {
+        SaliencyMethod::Vanilla => input_gradient(model, input, classes),
+        SaliencyMethod::Integrated => {
+            let steps = config.steps.max(1);
+            let mut total = input.zeros_like();
+            for step in 1..=steps {
+                let scaled = input.clone().mul_scalar(step as f32 / steps as f32);
+                total = total + input_gradient(model, scaled, classes.clone());
+            }
+            input * total.div_scalar(steps as f32)
+        }
+    };
+
+    let [batch_size, _, height, width] = attribution.dims();
+    let saliency = attribution.abs().max_dim(1).reshape([batch_size, height * width]);
+    let peak = saliency.clone().max_dim(1).clamp_min(1e-12);
+    (saliency / peak).reshape([batch_size, height, width])
+}
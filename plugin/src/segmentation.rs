@@ -0,0 +1,248 @@
+use std::path::Path;
+use burn::module::Module;
+use burn::nn::conv::{Conv2d, Conv2dConfig, ConvTranspose2d, ConvTranspose2dConfig};
+use burn::nn::pool::{MaxPool2d, MaxPool2dConfig};
+use burn::nn::{PaddingConfig2d, Relu};
+use burn::prelude::Backend;
+use burn::tensor::Tensor;
+use log::{error, info};
+use crate::config::SegmentationConfig;
+use crate::ErrNo;
+use crate::weights::load_record;
+
+const INPUT_DIM: usize = 4;
+
+/// Two 3x3 convolutions, each followed by ReLU.
+#[derive(Module, Debug)]
+pub struct DoubleConv<B: Backend> {
+    conv1: Conv2d<B>,
+    conv2: Conv2d<B>,
+    activation: Relu,
+}
+
+/// Classic UNet encoder-decoder with skip connections.
+#[derive(Module, Debug)]
+pub struct UNet<B: Backend> {
+    encoder: Vec<DoubleConv<B>>,
+    pool: MaxPool2d,
+    bottleneck: DoubleConv<B>,
+    upsample: Vec<ConvTranspose2d<B>>,
+    decoder: Vec<DoubleConv<B>>,
+    head: Conv2d<B>,
+}
+
+fn double_conv<B: Backend>(in_channels: usize, out_channels: usize, device: &B::Device) -> DoubleConv<B> {
+    let conv = |channels| Conv2dConfig::new(channels, [3, 3])
+        .with_padding(PaddingConfig2d::Explicit(1, 1))
+        .init(device);
+    DoubleConv {
+        conv1: conv([in_channels, out_channels]),
+        conv2: conv([out_channels, out_channels]),
+        activation: Relu::new(),
+    }
+}
+
+impl SegmentationConfig {
+    pub fn init<B: Backend>(&self, device: &B::Device) -> UNet<B> {
+        let channels = |level: usize| self.base_channels << level;
+
+        let encoder = (0..self.depth)
+            .map(|level| {
+                let in_channels = if level == 0 { self.in_channels } else { channels(level - 1) };
+                double_conv(in_channels, channels(level), device)
+            })
+            .collect();
+
+        // decoder levels run from the deepest to the shallowest
+        let upsample = (0..self.depth)
+            .rev()
+            .map(|level| ConvTranspose2dConfig::new([channels(level + 1), channels(level)], [2, 2])
+                .with_stride([2, 2])
+                .init(device))
+            .collect();
+        let decoder = (0..self.depth)
+            .rev()
+            .map(|level| double_conv(2 * channels(level), channels(level), device))
+            .collect();
+
+        UNet {
+            encoder,
+            pool: MaxPool2dConfig::new([2, 2]).init(),
+            bottleneck: double_conv(channels(self.depth - 1), channels(self.depth), device),
+            upsample,
+            decoder,
+            head: Conv2dConfig::new([channels(0), self.num_classes], [1, 1]).init(device),
+        }
+    }
+}
+
+impl<B: Backend> DoubleConv<B> {
+    pub fn forward(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
+        let x = self.activation.forward(self.conv1.forward(x));
+        self.activation.forward(self.conv2.forward(x))
+    }
+}
+
+impl<B: Backend> UNet<B> {
+    /// Returns per-pixel class logits `[N, classes, H, W]` at input resolution;
+    /// `H` and `W` must be divisible by `2^depth` for the skips to line up.
+    pub fn forward(&self, input: Tensor<B, 4>) -> Tensor<B, 4> {
+        let mut skips = Vec::with_capacity(self.encoder.len());
+        let mut x = input;
+        for block in &self.encoder {
+            x = block.forward(x);
+            skips.push(x.clone());
+            x = self.pool.forward(x);
+        }
+
+        x = self.bottleneck.forward(x);
+
+        for (upsample, block) in self.upsample.iter().zip(&self.decoder) {
+            let skip = skips.pop().unwrap();
+            x = block.forward(Tensor::cat(vec![skip, upsample.forward(x)], 1));
+        }
+
+        self.head.forward(x)
+    }
+}
+
+pub struct SegmentationModel<B: Backend> {
+    model: UNet<B>,
+    in_channels: usize,
+    /// Every image side must be a multiple of this, the downsampling of the bottleneck.
+    scale: usize,
+    device: B::Device,
+}
+
+pub struct SegmentationContext<B: Backend> {
+    pub input: Option<Tensor<B, INPUT_DIM>>,
+    pub output: Option<Tensor<B, INPUT_DIM>>,
+    in_channels: usize,
+    scale: usize,
+    device: B::Device,
+}
+
+impl<B: Backend> SegmentationModel<B> {
+    /// Builds a UNet from its architecture config and loads the weights from a burn record file.
+    pub fn new(path: &Path, config: &SegmentationConfig, device: &B::Device) -> Result<Self, ErrNo> {
+        // the bottleneck has `base_channels << depth` channels
+        let bottleneck_channels = u32::try_from(config.depth)
+            .ok()
+            .and_then(|depth| 1usize.checked_shl(depth))
+            .and_then(|factor| config.base_channels.checked_mul(factor));
+        if config.depth == 0 || config.num_classes == 0 || config.in_channels == 0 || !matches!(bottleneck_channels, Some(1..)) {
+            error!("Invalid UNet architecture: {:?}", config);
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let record = load_record::<B, _>(path, device).map_err(|err| {
+            error!("Failed to load UNet weights {}: {:?}", path.display(), err);
+            ErrNo::RuntimeError
+        })?;
+
+        info!("Loaded UNet: depth {}, {} classes", config.depth, config.num_classes);
+
+        let model = config.init::<B>(device).load_record(record);
+        Ok(SegmentationModel {
+            model,
+            in_channels: config.in_channels,
+            scale: 1 << config.depth,
+            device: device.clone(),
+        })
+    }
+
+    pub fn init_execution_context(&self) -> SegmentationContext<B> {
+        SegmentationContext {
+            input: None,
+            output: None,
+            in_channels: self.in_channels,
+            scale: self.scale,
+            device: self.device.clone(),
+        }
+    }
+
+    pub fn compute(&self, input: Tensor<B, INPUT_DIM>) -> Tensor<B, INPUT_DIM> {
+        self.model.forward(input)
+    }
+}
+
+impl<B: Backend> SegmentationContext<B> {
+    /// Takes `[N, in_channels, H, W]` images with `H` and `W` nonzero multiples of `2^depth`.
+    pub fn set_input(&mut self, input: &[f32], dimens: [usize; INPUT_DIM]) -> Result<(), ErrNo> {
+        let [batch_size, channels, height, width] = dimens;
+        let fits = |side: usize| side != 0 && side % self.scale == 0;
+        if batch_size == 0 || channels != self.in_channels || !fits(height) || !fits(width) {
+            error!(
+                "UNet expects [N, {}, H, W] images with sides divisible by {}, got {:?}",
+                self.in_channels, self.scale, dimens,
+            );
+            return Err(ErrNo::InvalidArgument);
+        }
+        let tensor = Tensor::<B, 1>::from_data(input, &self.device).reshape(dimens);
+        self.input = Some(tensor);
+        Ok(())
+    }
+
+    pub fn compute(&mut self, model: &SegmentationModel<B>) -> Result<(), ErrNo> {
+        let input = self.input.clone().ok_or(ErrNo::InvalidArgument)?;
+        self.output = Some(model.compute(input));
+        Ok(())
+    }
+
+    /// Output 0 is the `[N, C, H, W]` logits as `f32`; output 1 the `[N, H, W]`
+    /// argmax class mask as `u8`, reduced on the backend before download.
+    pub fn get_output(&self, index: i32) -> Result<Vec<u8>, ErrNo> {
+        let logits = self.output.clone().ok_or(ErrNo::RuntimeError)?;
+        match index {
+            0 => {
+                let logits = logits.into_data().convert::<f32>().to_vec::<f32>().map_err(|_| ErrNo::RuntimeError)?;
+                Ok(bytemuck::cast_slice(&logits).to_vec())
+            }
+            1 => {
+                let [_, classes, _, _] = logits.dims();
+                if classes > u8::MAX as usize + 1 {
+                    return Err(ErrNo::UnsupportedOperation);
+                }
+                logits.argmax(1)
+                    .into_data()
+                    .convert::<u8>()
+                    .to_vec::<u8>()
+                    .map_err(|_| ErrNo::RuntimeError)
+            }
+            _ => Err(ErrNo::InvalidArgument),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::backend::NdArray;
+    use super::*;
+
+    type TestBackend = NdArray<f32>;
+
+    #[test]
+    fn inputs_the_unet_cannot_take_are_rejected() {
+        let config = SegmentationConfig { base_channels: 4, depth: 2, ..SegmentationConfig::default() };
+        let device = Default::default();
+        let model = SegmentationModel::<TestBackend> {
+            model: config.init(&device),
+            in_channels: config.in_channels,
+            scale: 1 << config.depth,
+            device,
+        };
+        let mut context = model.init_execution_context();
+        let side = 1 << config.depth;
+
+        let wrong_channels = [1, config.in_channels + 1, side, side];
+        for dimens in [wrong_channels, [0, config.in_channels, side, side], [1, config.in_channels, side + 1, side], [1, config.in_channels, side, side / 2]] {
+            let input = vec![0.0; dimens.iter().product()];
+            assert_eq!(context.set_input(&input, dimens), Err(ErrNo::InvalidArgument), "{:?}", dimens);
+        }
+
+        let dimens = [1, config.in_channels, side, 2 * side];
+        context.set_input(&vec![0.0; dimens.iter().product()], dimens).unwrap();
+        context.compute(&model).unwrap();
+        assert_eq!(context.output.unwrap().dims(), [1, config.num_classes, side, 2 * side]);
+    }
+}
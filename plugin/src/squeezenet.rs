@@ -1,66 +1,408 @@
-use std::marker::PhantomData;
-use burn::prelude::{Backend, DeviceOps};
+use std::iter;
+use burn::backend::Autodiff;
+use burn::module::Module;
+use burn::nn::conv::{Conv2d, Conv2dConfig, Conv2dRecord};
+use burn::nn::pool::{MaxPool2d, MaxPool2dConfig};
+use burn::nn::{PaddingConfig2d, Relu};
+use burn::prelude::Backend;
+use burn::record::{BinBytesRecorder, FullPrecisionSettings, Recorder};
+use burn::tensor::activation::softmax;
+use burn::tensor::quantization::CalibrationRange;
 use burn::Tensor;
-use log::{debug, info};
+use log::error;
 use squeezenet_burn::model::squeezenet1::Model;
+use crate::config::QuantGranularity;
+use crate::quantization::{stored_bytes, QuantizedConv2d};
+use crate::ErrNo;
 
 const INPUT_DIM: usize = 4;
 const OUTPUT_DIM: usize = 2;
 
-pub struct SqueezenetModel<B: Backend> {
-    //model: i32 ,
-    model:  Model<B>,
-    _marker: PhantomData<B>,
+/// Fire modules of SqueezeNet 1.1 as `(input, squeeze, expand)` channels; each
+/// outputs `2 * expand` channels. `None` marks a max pooling between them.
+const FIRE_LAYOUT: [Option<(usize, usize, usize)>; 10] = [
+    Some((64, 16, 64)),
+    Some((128, 16, 64)),
+    None,
+    Some((128, 32, 128)),
+    Some((256, 32, 128)),
+    None,
+    Some((256, 48, 192)),
+    Some((384, 48, 192)),
+    Some((384, 64, 256)),
+    Some((512, 64, 256)),
+];
+
+const FEATURE_CHANNELS: usize = 512;
+/// Kernel size and stride of the max pooling between the fire modules.
+const POOL_KERNEL: usize = 3;
+const POOL_STRIDE: usize = 2;
+const NUM_CLASSES: usize = 1000;
+
+#[derive(Module, Debug)]
+pub struct Fire<B: Backend> {
+    squeeze: Conv2d<B>,
+    expand1x1: Conv2d<B>,
+    expand3x3: Conv2d<B>,
 }
 
-pub struct SqueezenetContext<B: Backend> {
-    pub input: Option<Tensor<B, INPUT_DIM>>,
-    pub output: Option<Tensor<B, OUTPUT_DIM>>
+/// SqueezeNet 1.1 rebuilt from the `squeezenet-burn` weights so the pooled
+/// features before the classifier are reachable.
+#[derive(Module, Debug)]
+pub struct SqueezeNet<B: Backend> {
+    conv1: Conv2d<B>,
+    fires: Vec<Fire<B>>,
+    pool: MaxPool2d,
+    classifier: Conv2d<B>,
+    activation: Relu,
 }
 
-impl<B: Backend> SqueezenetModel<B> {
-    pub fn new(device: &B::Device) -> Self {
-        //let model = 0;
-        let model = Model::new(device);
-        SqueezenetModel::<B> { model, _marker: Default::default() }
+/// SqueezeNet with int8 convolutions, quantized from the float network at load.
+#[derive(Module, Debug)]
+pub struct QuantizedSqueezeNet<B: Backend> {
+    /// Convolutions in the order of [`SqueezeNet::convs`].
+    convs: Vec<QuantizedConv2d<B>>,
+    pool: MaxPool2d,
+    activation: Relu,
+}
+
+/// Runs SqueezeNet 1.1 on `input`, with `conv(index, x)` applying convolution
+/// `index` in the order of [`SqueezeNet::convs`]; every convolution is followed
+/// by a ReLU. Returns the `[N, 512]` globally pooled features and the
+/// `[N, classes]` logits.
+fn run_graph<B, F>(
+    pool: &MaxPool2d,
+    activation: &Relu,
+    input: Tensor<B, INPUT_DIM>,
+    mut conv: F,
+) -> (Tensor<B, OUTPUT_DIM>, Tensor<B, OUTPUT_DIM>)
+where
+    B: Backend,
+    F: FnMut(usize, Tensor<B, INPUT_DIM>) -> Tensor<B, INPUT_DIM>,
+{
+    let mut index = 0;
+    let mut layer = |x: Tensor<B, INPUT_DIM>| {
+        let x = activation.forward(conv(index, x));
+        index += 1;
+        x
+    };
+
+    let mut x = ceil_max_pool(pool, layer(input));
+    for fire in FIRE_LAYOUT {
+        x = match fire {
+            Some(_) => {
+                let x = layer(x);
+                let expand1x1 = layer(x.clone());
+                let expand3x3 = layer(x);
+                Tensor::cat(vec![expand1x1, expand3x3], 1)
+            }
+            None => ceil_max_pool(pool, x),
+        };
     }
 
-    pub fn compute(&self, input: Tensor<B, INPUT_DIM>) -> Tensor<B, OUTPUT_DIM> {
-        self.model.forward(input)
+    let [batch_size, channels, _, _] = x.dims();
+    let features = x.clone().mean_dim(3).mean_dim(2).reshape([batch_size, channels]);
+
+    let logits = layer(x);
+    let [_, classes, _, _] = logits.dims();
+    let logits = logits.mean_dim(3).mean_dim(2).reshape([batch_size, classes]);
+
+    (features, logits)
+}
 
-        //let shape = input.dims(); // e.g. [B, C, H, W]
-        //let batch_size = shape[0];
-        //let flatten_dim = shape[1] * shape[2] * shape[3];
+impl<B: Backend> SqueezeNet<B> {
+    /// Builds the layers in graph order, taking each convolution from `conv`
+    /// given its `(channels, kernel, stride, padding)`.
+    fn build<F>(num_classes: usize, mut conv: F) -> Self
+    where
+        F: FnMut([usize; 2], usize, usize, usize) -> Conv2d<B>,
+    {
+        let conv1 = conv([3, 64], 3, 2, 0);
+        let fires = FIRE_LAYOUT
+            .iter()
+            .flatten()
+            .map(|&(input, squeeze, expand)| Fire {
+                squeeze: conv([input, squeeze], 1, 1, 0),
+                expand1x1: conv([squeeze, expand], 1, 1, 0),
+                expand3x3: conv([squeeze, expand], 3, 1, 1),
+            })
+            .collect();
+        let classifier = conv([FEATURE_CHANNELS, num_classes], 1, 1, 0);
 
-        //let mut thing : Tensor<B, 2> = input.clone().reshape([batch_size, flatten_dim]);
+        SqueezeNet {
+            conv1,
+            fires,
+            pool: MaxPool2dConfig::new([POOL_KERNEL, POOL_KERNEL])
+                .with_strides([POOL_STRIDE, POOL_STRIDE])
+                .init(),
+            classifier,
+            activation: Relu::new(),
+        }
+    }
+
+    /// A randomly initialized network with `num_classes` outputs.
+    pub fn init(num_classes: usize, device: &B::Device) -> Self {
+        Self::build(num_classes, |channels, kernel, stride, padding| conv_config(channels, kernel, stride, padding).init(device))
+    }
+
+    /// Moves the convolution weights of the ONNX-generated model into our layers.
+    /// Its convolutions come in graph order: the stem, squeeze/expand1x1/expand3x3
+    /// of every fire module, then the classifier.
+    fn from_pretrained(device: &B::Device) -> Self {
+        let record = Model::<B>::new(device).into_record();
+        let mut records = [
+            record.conv2d1, record.conv2d2, record.conv2d3, record.conv2d4, record.conv2d5,
+            record.conv2d6, record.conv2d7, record.conv2d8, record.conv2d9, record.conv2d10,
+            record.conv2d11, record.conv2d12, record.conv2d13, record.conv2d14, record.conv2d15,
+            record.conv2d16, record.conv2d17, record.conv2d18, record.conv2d19, record.conv2d20,
+            record.conv2d21, record.conv2d22, record.conv2d23, record.conv2d24, record.conv2d25,
+            record.conv2d26,
+        ].into_iter();
+
+        Self::build(NUM_CLASSES, |channels, kernel, stride, padding| {
+            let record: Conv2dRecord<B> = records.next().expect("SqueezeNet has 26 convolutions");
+            let conv = conv_config(channels, kernel, stride, padding).init(device).load_record(record);
+            // catches a change in the numbering of the generated model, which
+            // `load_record` would otherwise accept with any shape
+            assert_eq!(
+                conv.weight.dims(),
+                [channels[1], channels[0], kernel, kernel],
+                "SqueezeNet convolutions are not in graph order",
+            );
+            conv
+        })
+    }
+
+    /// The convolutions in graph order: the stem, squeeze/expand1x1/expand3x3
+    /// of every fire module, then the classifier.
+    fn convs(&self) -> Vec<&Conv2d<B>> {
+        let fires = self.fires.iter().flat_map(|fire| [&fire.squeeze, &fire.expand1x1, &fire.expand3x3]);
+        iter::once(&self.conv1).chain(fires).chain(iter::once(&self.classifier)).collect()
+    }
 
-        //for _ in 0..5 {
-        //    // Reshape repeatedly (simulate load)
-        //    thing = (thing * 2).clone().reshape([batch_size, flatten_dim]);
-        //    println!("Computed tensor: {:?}", thing);
-        //}
-        //thing
+    pub fn num_classes(&self) -> usize {
+        self.classifier.weight.dims()[0]
+    }
+
+    /// Replaces the classifier with a randomly initialized one of `num_classes`
+    /// outputs, keeping the pretrained features.
+    pub fn with_classes(mut self, num_classes: usize, device: &B::Device) -> Self {
+        self.classifier = conv_config([FEATURE_CHANNELS, num_classes], 1, 1, 0).init(device);
+        self
+    }
+
+    /// Copies the network onto the autodiff backend through its serialized record,
+    /// for training or input gradients.
+    pub fn to_autodiff(&self, device: &B::Device) -> Result<SqueezeNet<Autodiff<B>>, ErrNo> {
+        let recorder = BinBytesRecorder::<FullPrecisionSettings>::default();
+        let record = Recorder::<B>::record(&recorder, self.clone().into_record(), ())
+            .and_then(|bytes| Recorder::<Autodiff<B>>::load(&recorder, bytes, device))
+            .map_err(|err| {
+                error!("Failed to copy SqueezeNet to the autodiff backend: {:?}", err);
+                ErrNo::RuntimeError
+            })?;
+        Ok(SqueezeNet::init(self.num_classes(), device).load_record(record))
+    }
+
+    /// Stops gradients of a layer: `conv1`, `fires`, `fires.<n>` or `classifier`.
+    pub fn freeze(mut self, layer: &str) -> Option<Self> {
+        match layer {
+            "conv1" => self.conv1 = self.conv1.no_grad(),
+            "classifier" => self.classifier = self.classifier.no_grad(),
+            "fires" => self.fires = self.fires.into_iter().map(Module::no_grad).collect(),
+            _ => {
+                let index: usize = layer.strip_prefix("fires.")?.parse().ok()?;
+                let fire = self.fires.get_mut(index)?;
+                *fire = fire.clone().no_grad();
+            }
+        }
+        Some(self)
+    }
+
+    /// Returns the `[N, 512]` globally pooled features and the `[N, classes]`
+    /// logits of one forward pass.
+    pub fn forward_logits(&self, input: Tensor<B, INPUT_DIM>) -> (Tensor<B, OUTPUT_DIM>, Tensor<B, OUTPUT_DIM>) {
+        let convs = self.convs();
+        run_graph(&self.pool, &self.activation, input, |index, x| convs[index].forward(x))
+    }
+
+    /// Returns the `[N, 512]` globally pooled features and the `[N, classes]` class
+    /// probabilities of one forward pass.
+    pub fn forward(&self, input: Tensor<B, INPUT_DIM>) -> (Tensor<B, OUTPUT_DIM>, Tensor<B, OUTPUT_DIM>) {
+        let (features, logits) = self.forward_logits(input);
+        (features, softmax(logits, 1))
+    }
+
+    /// The min-max range the input of every convolution takes over forward
+    /// passes of the samples, in the order of [`SqueezeNet::convs`]; all `None`
+    /// without samples.
+    pub fn input_ranges(&self, samples: &[Tensor<B, INPUT_DIM>]) -> Vec<Option<CalibrationRange<B>>> {
+        let convs = self.convs();
+        let mut ranges: Vec<Option<CalibrationRange<B>>> = vec![None; convs.len()];
+        for sample in samples {
+            run_graph(&self.pool, &self.activation, sample.clone(), |index, x| {
+                let (min, max) = (x.clone().min(), x.clone().max());
+                ranges[index] = Some(match ranges[index].take() {
+                    Some(range) => CalibrationRange { min: range.min.min_pair(min), max: range.max.max_pair(max) },
+                    None => CalibrationRange { min, max },
+                });
+                convs[index].forward(x)
+            });
+        }
+        ranges
     }
 }
 
-impl<B: Backend> SqueezenetContext<B> {
-    pub fn new() -> Self {
-        SqueezenetContext {
-            input: None,
-            output: None
+impl<B: Backend> QuantizedSqueezeNet<B> {
+    /// Quantizes every convolution of `network`; a range quantizes the input
+    /// of its convolution too.
+    pub fn new(network: &SqueezeNet<B>, granularity: QuantGranularity, ranges: Vec<Option<CalibrationRange<B>>>) -> Self {
+        QuantizedSqueezeNet {
+            convs: network
+                .convs()
+                .into_iter()
+                .zip(ranges)
+                .map(|(conv, range)| QuantizedConv2d::new(conv.clone(), granularity, range))
+                .collect(),
+            pool: network.pool.clone(),
+            activation: network.activation.clone(),
         }
     }
-    pub fn set_input(&mut self, input: &[B::FloatElem], dimens: [usize; INPUT_DIM]) {
-        let device: B::Device = Default::default();
-        debug!("B is: {}", std::any::type_name::<B>());
-        debug!("Selected device: {:?}", device.to_id());
 
-        let tensor = Tensor::<B, 1>::from_data(&*input, &device).reshape(dimens);
-        self.input = Some(tensor);
+    pub fn forward_logits(&self, input: Tensor<B, INPUT_DIM>) -> (Tensor<B, OUTPUT_DIM>, Tensor<B, OUTPUT_DIM>) {
+        run_graph(&self.pool, &self.activation, input, |index, x| self.convs[index].forward(x))
     }
-    pub fn get_output(&mut self) -> Vec<<B as Backend>::FloatElem> {
-        self.output.as_ref().unwrap()
-            .clone().into_data().to_vec()
-            .expect("Failed to get output data")
+}
+
+/// Max pooling in PyTorch's ceil mode, which SqueezeNet 1.1 was trained with: a
+/// partial last window is kept by padding the bottom and right. The inputs come
+/// out of a ReLU, so the zero padding never wins the max.
+fn ceil_max_pool<B: Backend>(pool: &MaxPool2d, x: Tensor<B, INPUT_DIM>) -> Tensor<B, INPUT_DIM> {
+    let [_, _, height, width] = x.dims();
+    let padding = |size: usize| (POOL_STRIDE - size.saturating_sub(POOL_KERNEL) % POOL_STRIDE) % POOL_STRIDE;
+    let (bottom, right) = (padding(height), padding(width));
+    let x = if bottom > 0 || right > 0 { x.pad((0, right, 0, bottom), 0.0) } else { x };
+    pool.forward(x)
+}
+
+fn conv_config(channels: [usize; 2], kernel: usize, stride: usize, padding: usize) -> Conv2dConfig {
+    Conv2dConfig::new(channels, [kernel, kernel])
+        .with_stride([stride, stride])
+        .with_padding(PaddingConfig2d::Explicit(padding, padding))
+}
+
+#[derive(Clone)]
+enum Network<B: Backend> {
+    Float(SqueezeNet<B>),
+    Int8(QuantizedSqueezeNet<B>),
+}
+
+#[derive(Clone)]
+pub struct SqueezenetModel<B: Backend> {
+    model: Network<B>,
+    normalize: bool,
+}
+
+impl<B: Backend> SqueezenetModel<B> {
+    pub fn new(normalize: bool, device: &B::Device) -> Self {
+        SqueezenetModel { model: Network::Float(SqueezeNet::from_pretrained(device)), normalize }
+    }
+
+    /// Wraps an already built network, e.g. one fine-tuned by a training session.
+    pub fn from_network(model: SqueezeNet<B>, normalize: bool) -> Self {
+        SqueezenetModel { model: Network::Float(model), normalize }
+    }
+
+    /// The float network; `None` once quantized.
+    pub fn network(&self) -> Option<&SqueezeNet<B>> {
+        match &self.model {
+            Network::Float(network) => Some(network),
+            Network::Int8(_) => None,
+        }
+    }
+
+    pub fn normalize(&self) -> bool {
+        self.normalize
+    }
+
+    /// Swaps the network for an int8 one. With calibration samples the input
+    /// of every convolution is quantized too, scaled to the range it takes on them.
+    pub fn quantize(self, granularity: QuantGranularity, samples: &[Tensor<B, INPUT_DIM>]) -> Self {
+        let network = match self.model {
+            Network::Float(network) => QuantizedSqueezeNet::new(&network, granularity, network.input_ranges(samples)),
+            Network::Int8(network) => network,
+        };
+        SqueezenetModel { model: Network::Int8(network), normalize: self.normalize }
+    }
+
+    /// Bytes of the convolution weights, biases and scales as stored on the device.
+    pub fn weight_bytes(&self) -> usize {
+        match &self.model {
+            Network::Float(network) => network
+                .convs()
+                .into_iter()
+                .map(|conv| stored_bytes(conv.weight.val()) + conv.bias.as_ref().map_or(0, |bias| stored_bytes(bias.val())))
+                .sum(),
+            Network::Int8(network) => network.convs.iter().map(QuantizedConv2d::weight_bytes).sum(),
+        }
+    }
+
+    /// Returns the pooled features, L2-normalized if configured, with the class probabilities.
+    pub fn compute(&self, input: Tensor<B, INPUT_DIM>) -> (Tensor<B, OUTPUT_DIM>, Tensor<B, OUTPUT_DIM>) {
+        let (features, logits) = match &self.model {
+            Network::Float(network) => network.forward_logits(input),
+            Network::Int8(network) => network.forward_logits(input),
+        };
+        let probabilities = softmax(logits, 1);
+        let features = if self.normalize {
+            let norm = features.clone().powf_scalar(2.0).sum_dim(1).sqrt().clamp_min(1e-12);
+            features / norm
+        } else {
+            features
+        };
+        (features, probabilities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::backend::NdArray;
+    use burn::tensor::{Distribution, Int, TensorData, Tolerance};
+    use super::*;
+
+    type TestBackend = NdArray<f32>;
+
+    fn assert_matches_reference(size: usize) {
+        let device = Default::default();
+        let reference = Model::<TestBackend>::new(&device);
+        let network = SqueezeNet::<TestBackend>::from_pretrained(&device);
+
+        let input = Tensor::<TestBackend, INPUT_DIM>::random([2, 3, size, size], Distribution::Normal(0.0, 1.0), &device);
+        let expected = reference.forward(input.clone());
+        let (_, probabilities) = network.forward(input);
+
+        probabilities.into_data().assert_approx_eq::<f32>(&expected.into_data(), Tolerance::rel_abs(1e-4, 1e-5));
+    }
+
+    #[test]
+    fn forward_matches_reference_at_224() {
+        assert_matches_reference(224);
+    }
+
+    /// Every pooling input of a 256x256 image has an odd size, where floor and
+    /// ceil mode agree whichever rounding the generated model uses.
+    #[test]
+    fn forward_matches_reference_at_256() {
+        assert_matches_reference(256);
+    }
+
+    #[test]
+    fn pooling_keeps_partial_last_window() {
+        let device = Default::default();
+        let pool = MaxPool2dConfig::new([POOL_KERNEL, POOL_KERNEL]).with_strides([POOL_STRIDE, POOL_STRIDE]).init();
+        let input = Tensor::<TestBackend, 1, Int>::arange(0..16, &device).float().reshape([1, 1, 4, 4]);
+
+        let output = ceil_max_pool(&pool, input);
+        output.into_data().assert_eq(&TensorData::new(vec![10f32, 11.0, 14.0, 15.0], [1, 1, 2, 2]), false);
     }
 }
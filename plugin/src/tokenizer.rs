@@ -0,0 +1,145 @@
+use std::path::Path;
+use log::{error, info};
+use tokenizers::Tokenizer;
+use crate::ErrNo;
+
+/// File name of the HuggingFace tokenizer expected next to a text model.
+const TOKENIZER_FILE: &str = "tokenizer.json";
+
+/// Token ids and attention mask of a padded `[batch, seq]` batch, row-major.
+pub struct TokenBatch {
+    pub input_ids: Vec<i64>,
+    pub attention_mask: Vec<i64>,
+    pub batch_size: usize,
+    pub seq_length: usize,
+}
+
+/// Host-side HuggingFace tokenizer shared by the graph and its contexts.
+pub struct TextTokenizer {
+    tokenizer: Tokenizer,
+    pad_id: u32,
+}
+
+impl TextTokenizer {
+    /// Loads `tokenizer.json` from a model directory; `None` if the model has none.
+    pub fn from_dir(path: &Path) -> Option<Self> {
+        let file = path.join(TOKENIZER_FILE);
+        if !file.exists() {
+            return None;
+        }
+
+        match Tokenizer::from_file(&file) {
+            Ok(tokenizer) => {
+                let pad_id = tokenizer.get_padding().map(|padding| padding.pad_id).unwrap_or(0);
+                info!("Loaded tokenizer: {}", file.display());
+                Some(TextTokenizer { tokenizer, pad_id })
+            }
+            Err(err) => {
+                error!("Failed to load tokenizer {}: {}", file.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Encodes one text into ids and attention mask, truncated to `max_tokens`.
+    pub fn encode(&self, text: &str, max_tokens: usize) -> Result<(Vec<i64>, Vec<i64>), ErrNo> {
+        let encoding = self.tokenizer.encode(text, true).map_err(|err| {
+            error!("Failed to tokenize text: {}", err);
+            ErrNo::InvalidEncoding
+        })?;
+
+        let length = encoding.get_ids().len().min(max_tokens);
+        let ids = encoding.get_ids()[..length].iter().map(|&id| id as i64).collect();
+        let mask = encoding.get_attention_mask()[..length].iter().map(|&m| m as i64).collect();
+        Ok((ids, mask))
+    }
+
+    /// Encodes several texts and right-pads them to the longest one.
+    pub fn encode_batch(&self, texts: &[&str], max_tokens: usize) -> Result<TokenBatch, ErrNo> {
+        let encoded = texts
+            .iter()
+            .map(|text| self.encode(text, max_tokens))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let batch_size = encoded.len();
+        let seq_length = encoded.iter().map(|(ids, _)| ids.len()).max().unwrap_or(0);
+
+        let mut input_ids = Vec::with_capacity(batch_size * seq_length);
+        let mut attention_mask = Vec::with_capacity(batch_size * seq_length);
+        for (ids, mask) in encoded {
+            let padding = seq_length - ids.len();
+            input_ids.extend(ids);
+            input_ids.extend(std::iter::repeat(self.pad_id as i64).take(padding));
+            attention_mask.extend(mask);
+            attention_mask.extend(std::iter::repeat(0).take(padding));
+        }
+
+        Ok(TokenBatch { input_ids, attention_mask, batch_size, seq_length })
+    }
+
+    /// Number of ids the tokenizer knows, added tokens included.
+    pub fn vocab_size(&self) -> usize {
+        self.tokenizer.get_vocab_size(true)
+    }
+
+    /// Decodes ids back to text; ids outside the vocabulary are rejected.
+    pub fn decode(&self, ids: &[i64]) -> Result<String, ErrNo> {
+        let vocab_size = self.vocab_size();
+        if let Some(id) = ids.iter().find(|&&id| id < 0 || id as usize >= vocab_size) {
+            error!("Token id {} is outside the vocabulary of {}", id, vocab_size);
+            return Err(ErrNo::InvalidArgument);
+        }
+        let ids: Vec<u32> = ids.iter().map(|&id| id as u32).collect();
+        self.tokenizer.decode(&ids, true).map_err(|err| {
+            error!("Failed to decode tokens: {}", err);
+            ErrNo::InvalidArgument
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::str::FromStr;
+    use super::*;
+
+    /// Word-level tokenizer splitting on whitespace, with `[PAD]` as id 0.
+    const TOKENIZER_JSON: &str = r#"{
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": null,
+        "decoder": null,
+        "model": {"type": "WordLevel", "vocab": {"[PAD]": 0, "[UNK]": 1, "hello": 2, "world": 3}, "unk_token": "[UNK]"}
+    }"#;
+
+    pub(crate) fn tokenizer() -> TextTokenizer {
+        TextTokenizer { tokenizer: Tokenizer::from_str(TOKENIZER_JSON).unwrap(), pad_id: 0 }
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let tokenizer = tokenizer();
+        let (ids, mask) = tokenizer.encode("hello world", 16).unwrap();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(mask, vec![1, 1]);
+        assert_eq!(tokenizer.decode(&ids).unwrap(), "hello world");
+    }
+
+    #[test]
+    fn encode_batch_pads_to_longest() {
+        let batch = tokenizer().encode_batch(&["hello world", "world"], 16).unwrap();
+        assert_eq!((batch.batch_size, batch.seq_length), (2, 2));
+        assert_eq!(batch.input_ids, vec![2, 3, 3, 0]);
+        assert_eq!(batch.attention_mask, vec![1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn decode_rejects_ids_outside_vocab() {
+        let tokenizer = tokenizer();
+        assert!(matches!(tokenizer.decode(&[4]), Err(ErrNo::InvalidArgument)));
+        assert!(matches!(tokenizer.decode(&[-1]), Err(ErrNo::InvalidArgument)));
+    }
+}
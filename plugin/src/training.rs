@@ -0,0 +1,156 @@
+use burn::backend::Autodiff;
+use burn::module::AutodiffModule;
+use burn::nn::loss::CrossEntropyLossConfig;
+use burn::optim::adaptor::OptimizerAdaptor;
+use burn::optim::momentum::MomentumConfig;
+use burn::optim::{Adam, AdamConfig, GradientsParams, Optimizer, Sgd, SgdConfig};
+use burn::prelude::Backend;
+use burn::tensor::{Int, Tensor};
+use log::{debug, error, info};
+use crate::classifier::{ClassifierGraph, ClassifierModel, MIN_NATIVE_SIZE};
+use crate::config::{ClassificationConfig, OptimizerKind, TrainingConfig};
+use crate::ErrNo;
+use crate::squeezenet::{SqueezeNet, SqueezenetModel};
+
+const INPUT_DIM: usize = 4;
+
+type TrainingBackend<B> = Autodiff<B>;
+
+enum TrainingOptimizer<B: Backend> {
+    Sgd(OptimizerAdaptor<Sgd<B>, SqueezeNet<TrainingBackend<B>>, TrainingBackend<B>>),
+    Adam(OptimizerAdaptor<Adam, SqueezeNet<TrainingBackend<B>>, TrainingBackend<B>>),
+}
+
+/// Fine-tunes a copy of a SqueezeNet classifier graph on labeled batches. The
+/// source graph is left untouched; `finish` turns the result into a new graph.
+pub struct TrainingSession<B: Backend> {
+    /// Taken by every optimizer step, which consumes and returns the network.
+    model: Option<SqueezeNet<TrainingBackend<B>>>,
+    optimizer: TrainingOptimizer<B>,
+    learning_rate: f64,
+    normalize: bool,
+    classification: ClassificationConfig,
+    steps: usize,
+    device: B::Device,
+}
+
+impl<B: Backend> TrainingSession<B> {
+    pub fn new(graph: &ClassifierGraph<B>, config: &TrainingConfig) -> Result<Self, ErrNo> {
+        let model = match &graph.model {
+            ClassifierModel::Squeezenet(model) => model,
+            _ => return Err(ErrNo::UnsupportedOperation),
+        };
+        if config.learning_rate <= 0.0 || config.num_classes == Some(0) {
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let device = graph.device().clone();
+        let network = model.network().ok_or_else(|| {
+            error!("Quantized models cannot be fine-tuned");
+            ErrNo::UnsupportedOperation
+        })?;
+        let mut network = network.to_autodiff(&device)?;
+        if let Some(num_classes) = config.num_classes {
+            network = network.with_classes(num_classes, &device);
+        }
+        for layer in &config.frozen {
+            network = network.freeze(layer).ok_or_else(|| {
+                error!("Unknown layer to freeze: {}", layer);
+                ErrNo::InvalidArgument
+            })?;
+        }
+
+        if config.labels.as_ref().is_some_and(|labels| labels.len() != network.num_classes()) {
+            error!("Training needs one label per class of the {}-class head", network.num_classes());
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let optimizer = match config.optimizer {
+            OptimizerKind::Sgd => TrainingOptimizer::Sgd(
+                SgdConfig::new()
+                    .with_momentum(config.momentum.map(|momentum| MomentumConfig::new().with_momentum(momentum)))
+                    .init(),
+            ),
+            OptimizerKind::Adam => TrainingOptimizer::Adam(AdamConfig::new().init()),
+        };
+
+        // a new head without names is labeled by class index, not with ImageNet names
+        let mut classification = graph.config().clone();
+        if config.labels.is_some() || config.num_classes.is_some() {
+            classification.labels = config.labels.clone().or_else(|| {
+                config.num_classes.map(|num_classes| (0..num_classes).map(|class| class.to_string()).collect())
+            });
+        }
+
+        info!(
+            "Training session: {:?} at {}, {} classes, frozen {:?}",
+            config.optimizer, config.learning_rate, network.num_classes(), config.frozen
+        );
+
+        Ok(TrainingSession {
+            model: Some(network),
+            optimizer,
+            learning_rate: config.learning_rate,
+            normalize: model.normalize(),
+            classification,
+            steps: 0,
+            device,
+        })
+    }
+
+    /// Runs one optimizer step on `[N, 3, H, W]` images and their `N` class
+    /// indices, returning the mean cross-entropy loss before the update.
+    pub fn step(&mut self, images: &[f32], dimens: [usize; INPUT_DIM], labels: &[i64]) -> Result<f32, ErrNo> {
+        let [batch_size, channels, height, width] = dimens;
+        if channels != 3 || batch_size == 0 || labels.len() != batch_size {
+            return Err(ErrNo::InvalidArgument);
+        }
+        // smaller images do not survive SqueezeNet's downsampling
+        if height < MIN_NATIVE_SIZE || width < MIN_NATIVE_SIZE {
+            error!("Training images must be at least {}x{}, got {}x{}", MIN_NATIVE_SIZE, MIN_NATIVE_SIZE, height, width);
+            return Err(ErrNo::InvalidArgument);
+        }
+        let num_classes = self.model.as_ref().ok_or(ErrNo::RuntimeError)?.num_classes() as i64;
+        if labels.iter().any(|&label| label < 0 || label >= num_classes) {
+            return Err(ErrNo::InvalidArgument);
+        }
+
+        let input = Tensor::<TrainingBackend<B>, 1>::from_data(images, &self.device).reshape(dimens);
+        let targets = Tensor::<TrainingBackend<B>, 1, Int>::from_data(labels, &self.device);
+
+        // nothing fallible runs while the model is taken out of the session
+        let model = self.model.take().ok_or(ErrNo::RuntimeError)?;
+        let (_, logits) = model.forward_logits(input);
+        let loss = CrossEntropyLossConfig::new().init(&self.device).forward(logits, targets);
+        let grads = GradientsParams::from_grads(loss.backward(), &model);
+        let model = match &mut self.optimizer {
+            TrainingOptimizer::Sgd(optimizer) => optimizer.step(self.learning_rate, model, grads),
+            TrainingOptimizer::Adam(optimizer) => optimizer.step(self.learning_rate, model, grads),
+        };
+        self.model = Some(model);
+        self.steps += 1;
+
+        let value = loss
+            .into_data()
+            .convert::<f32>()
+            .to_vec::<f32>()
+            .ok()
+            .and_then(|values| values.first().copied())
+            .ok_or(ErrNo::RuntimeError)?;
+
+        debug!("Training step {}: loss {}", self.steps, value);
+        Ok(value)
+    }
+
+    /// Returns the trained network as a classifier graph for normal inference.
+    pub fn finish(self) -> Result<ClassifierGraph<B>, ErrNo> {
+        let network = self.model.ok_or(ErrNo::RuntimeError)?.valid();
+        info!("Finished training after {} steps", self.steps);
+
+        ClassifierGraph::new(
+            ClassifierModel::Squeezenet(SqueezenetModel::from_network(network, self.normalize)),
+            self.classification,
+            &self.device,
+        )
+    }
+}
@@ -3,48 +3,666 @@ use wasmedge_plugin_sdk::error::CoreError;
 use wasmedge_plugin_sdk::memory::Memory;
 use wasmedge_plugin_sdk::types::WasmVal;
 use std::mem;
-use std::sync::Mutex;
-use burn::backend::{NdArray, Wgpu};
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+use burn::backend::{Cpu, NdArray, Wgpu};
+use burn::backend::cpu::CpuDevice;
 use burn::backend::ndarray::NdArrayDevice;
 use burn::backend::wgpu::WgpuDevice;
 use burn::prelude::{Backend, DeviceOps};
+use burn::tensor::{bf16, f16};
 use wasmedge_wasi_nn::TensorType;
 use crate::{ErrNo, WasiTensorData};
-use crate::helper::get_slice;
-use crate::squeezenet::{SqueezenetContext, SqueezenetModel};
+use crate::bert::{BertModel, EmbeddingContext};
+use crate::gpt2::{GenerationContext, Gpt2Model};
+use crate::tokenizer::TextTokenizer;
+use crate::classifier::{ClassifierContext, ClassifierGraph, ClassifierModel};
+use crate::clip::{ClipContext, ClipModel};
+use crate::backends::get_backends;
+use crate::config::{
+    ClipMode, CpuBackend, GpuDevice, GpuFallback, GraphConfig, ModelKind, Precision, Target, TrainingConfig, PLUGIN_CONFIG,
+};
+use crate::detection::{DetectorContext, DetectorModel};
+use crate::helper::{get_slice, read_pod};
+use crate::mobilenet::MobileNetV2Model;
+use crate::ocr::{OcrContext, OcrModel};
+use crate::preprocess::{imagenet_tensor, read_image};
+use crate::recurrent::{RecurrentContext, RecurrentModel};
+use crate::resnet::ResnetModel;
+use crate::segmentation::{SegmentationContext, SegmentationModel};
+use crate::squeezenet::SqueezenetModel;
+use crate::training::TrainingSession;
+use crate::weights::{digest_samples, WeightDigests};
 use crate::whisper::{WhisperContext, WhisperModel};
-use log::{info, debug, error};
+use log::{info, debug, error, warn};
 
-const INPUT_DIM: usize = 4;
+const IMAGE_DIM: usize = 4;
+
+/// Graph encoding id of burn in wasi-nn.
+const BURN_ENCODING: i32 = 8;
 
 type NdArrayBackend = NdArray<f32>;
+type NdArrayF64Backend = NdArray<f64>;
 type WgpuBackend = Wgpu;
+type WgpuF16Backend = Wgpu<f16, i32>;
+type WgpuBf16Backend = Wgpu<bf16, i32>;
+type CubeCpuBackend = Cpu;
 
 pub enum Graph<B: Backend> {
-    Squeezenet(SqueezenetModel<B>),
+    Classifier(ClassifierGraph<B>),
+    Embedding(BertModel<B>),
+    Generation(Gpt2Model<B>),
+    Detection(DetectorModel<B>),
+    Segmentation(SegmentationModel<B>),
+    Clip(ClipModel<B>),
+    Ocr(OcrModel<B>),
+    Recurrent(RecurrentModel<B>),
     Whisper(WhisperModel<B>),
 }
 
 pub enum GraphWithBackend {
     WithWgpu(Graph<WgpuBackend>),
+    WithWgpuF16(Graph<WgpuF16Backend>),
+    WithWgpuBf16(Graph<WgpuBf16Backend>),
     WithNdArray(Graph<NdArrayBackend>),
+    WithNdArrayF64(Graph<NdArrayF64Backend>),
+    WithCpu(Graph<CubeCpuBackend>),
 }
 
 pub enum Context<B: Backend> {
-    Squeezenet(SqueezenetContext<B>),
+    Classifier(ClassifierContext<B>),
+    Embedding(EmbeddingContext<B>),
+    Generation(GenerationContext<B>),
+    Detection(DetectorContext<B>),
+    Segmentation(SegmentationContext<B>),
+    Clip(ClipContext<B>),
+    Ocr(OcrContext<B>),
+    Recurrent(RecurrentContext<B>),
     Whisper(WhisperContext<B>),
 }
 
 pub enum ContextWithBackend {
     WithWgpu(Context<WgpuBackend>),
+    WithWgpuF16(Context<WgpuF16Backend>),
+    WithWgpuBf16(Context<WgpuBf16Backend>),
     WithNdArray(Context<NdArrayBackend>),
+    WithNdArrayF64(Context<NdArrayF64Backend>),
+    WithCpu(Context<CubeCpuBackend>),
+}
+
+/// Fine-tuning sessions exist for the f32 graphs of the NdArray and Wgpu backends.
+pub enum TrainingWithBackend {
+    WithWgpu(TrainingSession<WgpuBackend>),
+    WithNdArray(TrainingSession<NdArrayBackend>),
+}
+
+/// Evaluates `$body` with `$graph` bound to the backend-specific graph.
+macro_rules! with_graph {
+    ($handle:expr, $graph:ident => $body:expr) => {
+        match $handle {
+            GraphWithBackend::WithWgpu($graph) => $body,
+            GraphWithBackend::WithWgpuF16($graph) => $body,
+            GraphWithBackend::WithWgpuBf16($graph) => $body,
+            GraphWithBackend::WithNdArray($graph) => $body,
+            GraphWithBackend::WithNdArrayF64($graph) => $body,
+            GraphWithBackend::WithCpu($graph) => $body,
+        }
+    };
+}
+
+/// Evaluates `$body` with `$context` bound to the backend-specific context.
+macro_rules! with_context {
+    ($handle:expr, $context:ident => $body:expr) => {
+        match $handle {
+            ContextWithBackend::WithWgpu($context) => $body,
+            ContextWithBackend::WithWgpuF16($context) => $body,
+            ContextWithBackend::WithWgpuBf16($context) => $body,
+            ContextWithBackend::WithNdArray($context) => $body,
+            ContextWithBackend::WithNdArrayF64($context) => $body,
+            ContextWithBackend::WithCpu($context) => $body,
+        }
+    };
+}
+
+/// Like `with_context`, also binding the graph the context was created from.
+/// A context and graph on different backends yield `InvalidArgument`.
+macro_rules! with_context_and_graph {
+    ($context_handle:expr, $graph_handle:expr, $context:ident, $graph:ident => $body:expr) => {
+        match ($context_handle, $graph_handle) {
+            (ContextWithBackend::WithWgpu($context), GraphWithBackend::WithWgpu($graph)) => $body,
+            (ContextWithBackend::WithWgpuF16($context), GraphWithBackend::WithWgpuF16($graph)) => $body,
+            (ContextWithBackend::WithWgpuBf16($context), GraphWithBackend::WithWgpuBf16($graph)) => $body,
+            (ContextWithBackend::WithNdArray($context), GraphWithBackend::WithNdArray($graph)) => $body,
+            (ContextWithBackend::WithNdArrayF64($context), GraphWithBackend::WithNdArrayF64($graph)) => $body,
+            (ContextWithBackend::WithCpu($context), GraphWithBackend::WithCpu($graph)) => $body,
+            _ => Err(ErrNo::InvalidArgument),
+        }
+    };
+}
+
+/// Input tensor data as read from guest memory; integer types are widened to `i64`.
+pub enum InputData<'a> {
+    F32(Vec<f32>),
+    I64(Vec<i64>),
+    U8(&'a [u8]),
+}
+
+impl<B: Backend> Graph<B> {
+    /// `samples` are the encoded calibration images of a quantized classifier.
+    pub fn new(kind: ModelKind, config: &GraphConfig, samples: &[Vec<u8>], device: &B::Device) -> Result<Self, ErrNo> {
+        let graph = match kind {
+            ModelKind::Squeezenet => Graph::Classifier(ClassifierGraph::new(
+                ClassifierModel::Squeezenet(SqueezenetModel::new(config.normalize.unwrap_or(true), device)),
+                config.classification.clone(),
+                device,
+            )?),
+            ModelKind::Resnet18 | ModelKind::Resnet50 => Graph::Classifier(ClassifierGraph::new(
+                ClassifierModel::Resnet(ResnetModel::new(kind, device).ok_or(ErrNo::RuntimeError)?),
+                config.classification.clone(),
+                device,
+            )?),
+            ModelKind::Mobilenetv2 => Graph::Classifier(ClassifierGraph::new(
+                ClassifierModel::MobileNetV2(MobileNetV2Model::new(device).ok_or(ErrNo::RuntimeError)?),
+                config.classification.clone(),
+                device,
+            )?),
+            ModelKind::Bert => {
+                let path = config.path.as_ref().ok_or(ErrNo::InvalidArgument)?;
+                let normalize = config.normalize.unwrap_or(true);
+                Graph::Embedding(BertModel::new(Path::new(path), normalize, device)?)
+            }
+            ModelKind::Gpt2 => {
+                let path = config.path.as_ref().ok_or(ErrNo::InvalidArgument)?;
+                Graph::Generation(Gpt2Model::new(Path::new(path), config.generation.clone(), device)?)
+            }
+            ModelKind::YoloxNano | ModelKind::YoloxTiny | ModelKind::YoloxS => Graph::Detection(
+                DetectorModel::new(kind, config.detection.clone(), device).ok_or(ErrNo::RuntimeError)?
+            ),
+            ModelKind::Unet => {
+                let path = config.path.as_ref().ok_or(ErrNo::InvalidArgument)?;
+                Graph::Segmentation(SegmentationModel::new(Path::new(path), &config.segmentation, device)?)
+            }
+            ModelKind::Clip => {
+                let path = config.path.as_ref().ok_or(ErrNo::InvalidArgument)?;
+                Graph::Clip(ClipModel::new(Path::new(path), config.clip.clone(), device)?)
+            }
+            ModelKind::Crnn => {
+                let path = config.path.as_ref().ok_or(ErrNo::InvalidArgument)?;
+                Graph::Ocr(OcrModel::new(Path::new(path), &config.ocr, device)?)
+            }
+            ModelKind::Recurrent => {
+                let path = config.path.as_ref().ok_or(ErrNo::InvalidArgument)?;
+                Graph::Recurrent(RecurrentModel::new(Path::new(path), &config.recurrent, device)?)
+            }
+        };
+        match (graph, &config.quantization) {
+            (graph, None) => Ok(graph),
+            (Graph::Classifier(classifier), Some(quantization)) => Ok(Graph::Classifier(classifier.quantize(quantization, samples)?)),
+            (_, Some(_)) => Err(ErrNo::UnsupportedOperation),
+        }
+    }
+
+    /// Whether the graph's first input is token ids rather than float data.
+    fn takes_tokens(&self, config: &GraphConfig) -> bool {
+        match self {
+            Graph::Embedding(_) | Graph::Generation(_) => true,
+            Graph::Clip(_) => config.clip.mode == ClipMode::Text,
+            _ => false,
+        }
+    }
+
+    /// Shape of a single input of the usual size, used when the warm-up
+    /// config does not set one.
+    fn warmup_shape(&self, config: &GraphConfig) -> Option<Vec<usize>> {
+        if self.takes_tokens(config) {
+            return Some(vec![1, 8]);
+        }
+        match self {
+            Graph::Classifier(_) | Graph::Clip(_) => Some(vec![1, 3, 224, 224]),
+            Graph::Detection(_) => Some(vec![1, 3, 640, 640]),
+            Graph::Segmentation(_) => {
+                let side = 256.max(1 << config.segmentation.depth);
+                Some(vec![1, config.segmentation.in_channels, side, side])
+            }
+            Graph::Ocr(_) => Some(vec![1, 1, 32, 128]),
+            Graph::Recurrent(_) => Some(vec![1, 1, config.recurrent.input_size]),
+            _ => None,
+        }
+    }
+
+    /// Runs `passes` forward passes on zeroed input in a throwaway context.
+    pub fn warm_up(&self, config: &GraphConfig, passes: usize) -> Result<(), ErrNo> {
+        let shape = match &config.warmup.shape {
+            Some(shape) => shape.clone(),
+            None => self.warmup_shape(config).ok_or(ErrNo::UnsupportedOperation)?,
+        };
+        let len = shape.iter().product();
+        let floats = vec![0f32; len];
+
+        let mut context = self.init_execution_context()?;
+        for _ in 0..passes {
+            let input = if self.takes_tokens(config) {
+                InputData::I64(vec![0; len])
+            } else {
+                InputData::F32(floats.clone())
+            };
+            context.set_input(0, input, &shape)?;
+
+            match self {
+                // one step covers the whole prompt, as a first request would
+                Graph::Generation(_) => context.compute_single(self)?,
+                _ => context.compute(self)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn tokenizer(&self) -> Option<&TextTokenizer> {
+        match self {
+            Graph::Embedding(model) => model.tokenizer(),
+            Graph::Generation(model) => model.tokenizer(),
+            Graph::Clip(model) => model.tokenizer(),
+            _ => None,
+        }
+    }
+
+    pub fn init_execution_context(&self) -> Result<Context<B>, ErrNo> {
+        match self {
+            Graph::Classifier(graph) => Ok(Context::Classifier(graph.init_execution_context())),
+            Graph::Embedding(model) => Ok(Context::Embedding(model.init_execution_context())),
+            Graph::Generation(model) => Ok(Context::Generation(model.init_execution_context())),
+            Graph::Detection(model) => Ok(Context::Detection(model.init_execution_context())),
+            Graph::Segmentation(model) => Ok(Context::Segmentation(model.init_execution_context())),
+            Graph::Clip(model) => Ok(Context::Clip(model.init_execution_context())),
+            Graph::Ocr(model) => Ok(Context::Ocr(model.init_execution_context())),
+            Graph::Recurrent(model) => Ok(Context::Recurrent(model.init_execution_context())),
+            _ => Err(ErrNo::UnsupportedOperation),
+        }
+    }
+}
+
+impl<B: Backend> Context<B> {
+    pub fn set_input(&mut self, index: i32, data: InputData, dimensions: &[usize]) -> Result<(), ErrNo> {
+        match (self, data) {
+            (Context::Classifier(context), InputData::F32(tensor)) => {
+                if index != 0 {
+                    return Err(ErrNo::UnsupportedOperation);
+                }
+                let dimensions: [usize; IMAGE_DIM] = dimensions.try_into().map_err(|_| ErrNo::InvalidArgument)?;
+                context.set_input(&tensor, dimensions)
+            }
+            (Context::Classifier(context), InputData::U8(bytes)) => {
+                if index != 0 {
+                    return Err(ErrNo::UnsupportedOperation);
+                }
+                let image = read_image(bytes, dimensions)?;
+                let (tensor, dimensions) = imagenet_tensor(&image)?;
+                context.set_input(&tensor, dimensions)
+            }
+            (Context::Detection(context), InputData::F32(tensor)) => {
+                if index != 0 {
+                    return Err(ErrNo::UnsupportedOperation);
+                }
+                let dimensions: [usize; IMAGE_DIM] = dimensions.try_into().map_err(|_| ErrNo::InvalidArgument)?;
+                context.set_input(&tensor, dimensions)
+            }
+            (Context::Segmentation(context), InputData::F32(tensor)) => {
+                if index != 0 {
+                    return Err(ErrNo::UnsupportedOperation);
+                }
+                let dimensions: [usize; IMAGE_DIM] = dimensions.try_into().map_err(|_| ErrNo::InvalidArgument)?;
+                context.set_input(&tensor, dimensions)
+            }
+            (Context::Ocr(context), InputData::F32(tensor)) => {
+                if index != 0 {
+                    return Err(ErrNo::UnsupportedOperation);
+                }
+                let dimensions: [usize; IMAGE_DIM] = dimensions.try_into().map_err(|_| ErrNo::InvalidArgument)?;
+                context.set_input(&tensor, dimensions);
+                Ok(())
+            }
+            (Context::Recurrent(context), InputData::F32(tensor)) => context.set_input(index, &tensor, dimensions),
+            (Context::Clip(context), InputData::F32(tensor)) => {
+                if index != 0 {
+                    return Err(ErrNo::InvalidArgument);
+                }
+                let dimensions: [usize; IMAGE_DIM] = dimensions.try_into().map_err(|_| ErrNo::InvalidArgument)?;
+                context.set_images(&tensor, dimensions)
+            }
+            (Context::Clip(context), InputData::I64(tokens)) => {
+                if index != 0 {
+                    return Err(ErrNo::InvalidArgument);
+                }
+                let dimensions: [usize; 2] = dimensions.try_into().map_err(|_| ErrNo::InvalidArgument)?;
+                context.set_tokens(&tokens, dimensions)
+            }
+            (Context::Clip(context), InputData::U8(text)) => {
+                if index != 0 {
+                    return Err(ErrNo::InvalidArgument);
+                }
+                context.set_text(text)
+            }
+            (Context::Embedding(context), InputData::I64(tokens)) => {
+                let dimensions: [usize; 2] = dimensions.try_into().map_err(|_| ErrNo::InvalidArgument)?;
+                context.set_tokens(index, &tokens, dimensions)
+            }
+            (Context::Embedding(context), InputData::U8(text)) => {
+                if index != 0 {
+                    return Err(ErrNo::InvalidArgument);
+                }
+                context.set_text(text)
+            }
+            (Context::Generation(context), InputData::I64(tokens)) => {
+                if index != 0 {
+                    return Err(ErrNo::InvalidArgument);
+                }
+                let dimensions: [usize; 2] = dimensions.try_into().map_err(|_| ErrNo::InvalidArgument)?;
+                context.set_prompt_tokens(&tokens, dimensions)
+            }
+            (Context::Generation(context), InputData::U8(text)) => {
+                if index != 0 {
+                    return Err(ErrNo::InvalidArgument);
+                }
+                context.set_prompt_text(text)
+            }
+            _ => Err(ErrNo::UnsupportedOperation),
+        }
+    }
+
+    pub fn compute(&mut self, graph: &Graph<B>) -> Result<(), ErrNo> {
+        match (self, graph) {
+            (Context::Classifier(context), Graph::Classifier(graph)) => context.compute(graph),
+            (Context::Embedding(context), Graph::Embedding(model)) => context.compute(model),
+            (Context::Generation(context), Graph::Generation(model)) => context.compute(model),
+            (Context::Detection(context), Graph::Detection(model)) => context.compute(model),
+            (Context::Segmentation(context), Graph::Segmentation(model)) => context.compute(model),
+            (Context::Clip(context), Graph::Clip(model)) => context.compute(model),
+            (Context::Ocr(context), Graph::Ocr(model)) => context.compute(model),
+            (Context::Recurrent(context), Graph::Recurrent(model)) => context.compute(model),
+            _ => Err(ErrNo::UnsupportedOperation),
+        }
+    }
+
+    pub fn compute_single(&mut self, graph: &Graph<B>) -> Result<(), ErrNo> {
+        match (self, graph) {
+            (Context::Generation(context), Graph::Generation(model)) => context.step(model).map(|_| ()),
+            _ => Err(ErrNo::UnsupportedOperation),
+        }
+    }
+
+    pub fn get_output_single(&mut self, index: i32) -> Result<Vec<u8>, ErrNo> {
+        match self {
+            Context::Generation(context) => context.get_output_single(index),
+            _ => Err(ErrNo::UnsupportedOperation),
+        }
+    }
+
+    pub fn fini_single(&mut self) -> Result<(), ErrNo> {
+        match self {
+            Context::Generation(context) => {
+                context.reset();
+                Ok(())
+            }
+            Context::Recurrent(context) => {
+                context.reset();
+                Ok(())
+            }
+            _ => Err(ErrNo::UnsupportedOperation),
+        }
+    }
+
+    /// Applies per-context settings given as a JSON object.
+    pub fn configure(&mut self, json: &str) -> Result<(), ErrNo> {
+        match self {
+            Context::Generation(context) => context.configure(json),
+            Context::Classifier(context) => context.configure(json),
+            Context::Detection(context) => context.configure(json),
+            Context::Clip(context) => context.configure(json),
+            Context::Ocr(context) => context.configure(json),
+            _ => Err(ErrNo::UnsupportedOperation),
+        }
+    }
+
+    /// Returns the dimensions of the requested output as last computed.
+    pub fn get_output_shape(&mut self, index: i32) -> Result<Vec<usize>, ErrNo> {
+        let dims = match (self, index) {
+            (Context::Classifier(context), _) => return context.output_shape(index),
+            (Context::Embedding(context), 0) => context.output.as_ref().map(|output| output.dims().to_vec()),
+            (Context::Clip(context), 0) => context.output.as_ref().map(|output| output.dims().to_vec()),
+            (Context::Segmentation(context), 0 | 1) => context.output.as_ref().map(|output| {
+                let [batch_size, classes, height, width] = output.dims();
+                if index == 0 { vec![batch_size, classes, height, width] } else { vec![batch_size, height, width] }
+            }),
+            (Context::Recurrent(context), _) => return context.output_shape(index),
+            _ => return Err(ErrNo::UnsupportedOperation),
+        };
+        dims.ok_or(ErrNo::RuntimeError)
+    }
+
+    /// Returns the raw little-endian bytes of the requested output.
+    pub fn get_output(&mut self, index: i32) -> Result<Vec<u8>, ErrNo> {
+        let output = match self {
+            Context::Classifier(context) => {
+                if context.output.is_none() {
+                    return Err(ErrNo::RuntimeError);
+                }
+                if index != 0 {
+                    return context.get_post_processed(index);
+                }
+                context.get_output()
+            }
+            Context::Embedding(context) => {
+                if index != 0 {
+                    return Err(ErrNo::UnsupportedOperation);
+                }
+                if context.output.is_none() {
+                    return Err(ErrNo::RuntimeError);
+                }
+                context.get_output()
+            }
+            Context::Generation(context) => return context.get_output(index),
+            Context::Detection(context) => return context.get_output(index),
+            Context::Segmentation(context) => return context.get_output(index),
+            Context::Clip(context) => return context.get_output(index),
+            Context::Ocr(context) => return context.get_output(index),
+            Context::Recurrent(context) => return context.get_output(index),
+            _ => return Err(ErrNo::UnsupportedOperation),
+        };
+        Ok(bytemuck::cast_slice(&output).to_vec())
+    }
+}
+
+impl GraphWithBackend {
+    pub fn tokenizer(&self) -> Option<&TextTokenizer> {
+        with_graph!(self, graph => graph.tokenizer())
+    }
+
+    /// Name of the backend the graph was actually built on.
+    pub fn backend_name(&self) -> &'static str {
+        match self {
+            GraphWithBackend::WithWgpu(_)
+            | GraphWithBackend::WithWgpuF16(_)
+            | GraphWithBackend::WithWgpuBf16(_) => "wgpu",
+            GraphWithBackend::WithNdArray(_) | GraphWithBackend::WithNdArrayF64(_) => "ndarray",
+            GraphWithBackend::WithCpu(_) => "cpu",
+        }
+    }
+
+    /// Float element type of the graph's weights and activations.
+    pub fn precision(&self) -> Precision {
+        match self {
+            GraphWithBackend::WithWgpuF16(_) => Precision::F16,
+            GraphWithBackend::WithWgpuBf16(_) => Precision::Bf16,
+            GraphWithBackend::WithNdArrayF64(_) => Precision::F64,
+            _ => Precision::F32,
+        }
+    }
+
+    /// Graph metadata as guests read it through `get_graph_metadata`.
+    pub fn metadata(&self) -> serde_json::Value {
+        serde_json::json!({
+            "backend": self.backend_name(),
+            "precision": self.precision(),
+        })
+    }
+}
+
+fn status(result: Result<(), ErrNo>) -> Result<Vec<WasmVal>, CoreError> {
+    match result {
+        Ok(()) => Ok(vec![WasmVal::I32(ErrNo::Success as i32)]),
+        Err(err) => Ok(vec![WasmVal::I32(err as i32)]),
+    }
+}
+
+/// Copies an output into the guest buffer, failing with `TooLarge` if it does not fit.
+fn write_output(
+    output: Result<Vec<u8>, ErrNo>,
+    output_ptr: &i32,
+    output_max_size: &i32,
+    output_written_len_ptr: &i32,
+    memory: &mut Memory
+) -> Result<Vec<WasmVal>, CoreError> {
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => return Ok(vec![WasmVal::I32(err as i32)]),
+    };
+
+    if output.len() > *output_max_size as usize {
+        error!("Was output too large: {} > {}", output.len(), *output_max_size as usize);
+        return Ok(vec![WasmVal::I32(ErrNo::TooLarge as i32)]);
+    }
+
+    memory.write_bytes(&output, *output_ptr as u32).unwrap();
+    memory.write_data((*output_written_len_ptr as usize).into(), output.len() as u32);
+
+    Ok(vec![WasmVal::I32(ErrNo::Success as i32)])
+}
+
+/// Maps the configured adapter to a burn wgpu device. Adapters picked by name
+/// are translated to their index among adapters of the same type, which is how
+/// burn addresses them.
+fn select_wgpu_device(device: &GpuDevice) -> Option<WgpuDevice> {
+    let name = match device {
+        GpuDevice::Default => return Some(WgpuDevice::DefaultDevice),
+        GpuDevice::DiscreteGpu(index) => return Some(WgpuDevice::DiscreteGpu(*index)),
+        GpuDevice::IntegratedGpu(index) => return Some(WgpuDevice::IntegratedGpu(*index)),
+        GpuDevice::Cpu => return Some(WgpuDevice::Cpu),
+        GpuDevice::Name(name) => name.to_lowercase(),
+    };
+
+    let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+    let adapters: Vec<wgpu::AdapterInfo> = instance
+        .enumerate_adapters(wgpu::Backends::all())
+        .iter()
+        .map(|adapter| adapter.get_info())
+        .collect();
+
+    let (position, info) = adapters
+        .iter()
+        .enumerate()
+        .find(|(_, info)| info.name.to_lowercase().contains(&name))?;
+    let index = adapters[..position]
+        .iter()
+        .filter(|other| other.device_type == info.device_type)
+        .count();
+    debug!("Adapter {:?} matches {:?} as {:?} {}", info.name, name, info.device_type, index);
+
+    match info.device_type {
+        wgpu::DeviceType::DiscreteGpu => Some(WgpuDevice::DiscreteGpu(index)),
+        wgpu::DeviceType::IntegratedGpu => Some(WgpuDevice::IntegratedGpu(index)),
+        wgpu::DeviceType::VirtualGpu => Some(WgpuDevice::VirtualGpu(index)),
+        wgpu::DeviceType::Cpu => Some(WgpuDevice::Cpu),
+        wgpu::DeviceType::Other => None,
+    }
+}
+
+/// Builds a graph on the backend selected by the target and config: Wgpu when
+/// a wgpu device is given, otherwise the configured CPU backend.
+fn build_graph(
+    kind: ModelKind,
+    config: &GraphConfig,
+    samples: &[Vec<u8>],
+    wgpu_device: Option<WgpuDevice>,
+) -> Result<GraphWithBackend, ErrNo> {
+    match wgpu_device {
+        Some(device) => {
+            // 0:discrete, 1:integrated, 2:virtual, 3:cpu, 4:default
+            info!("Selected device: {:?}, {:?}, {:?}", device, device.to_id(), config.precision);
+
+            match config.precision {
+                Precision::F32 => Graph::<WgpuBackend>::new(kind, config, samples, &device).map(GraphWithBackend::WithWgpu),
+                Precision::F16 => Graph::<WgpuF16Backend>::new(kind, config, samples, &device).map(GraphWithBackend::WithWgpuF16),
+                Precision::Bf16 => Graph::<WgpuBf16Backend>::new(kind, config, samples, &device).map(GraphWithBackend::WithWgpuBf16),
+                Precision::F64 => Err(ErrNo::UnsupportedOperation),
+            }
+        }
+        None => match config.cpu_backend {
+            CpuBackend::Ndarray => {
+                let device = NdArrayDevice::default();
+                info!("Selected device: {:?}, {:?}, {:?}", device, device.to_id(), config.precision);
+
+                match config.precision {
+                    Precision::F32 => Graph::<NdArrayBackend>::new(kind, config, samples, &device).map(GraphWithBackend::WithNdArray),
+                    Precision::F64 => Graph::<NdArrayF64Backend>::new(kind, config, samples, &device).map(GraphWithBackend::WithNdArrayF64),
+                    Precision::F16 | Precision::Bf16 => Err(ErrNo::UnsupportedOperation),
+                }
+            }
+            CpuBackend::Cubecl if config.precision != Precision::F32 => Err(ErrNo::UnsupportedOperation),
+            CpuBackend::Cubecl => {
+                let device = CpuDevice::default();
+                info!("Selected device: {:?}, {:?}", device, device.to_id());
+
+                Graph::<CubeCpuBackend>::new(kind, config, samples, &device).map(GraphWithBackend::WithCpu)
+            }
+        },
+    }
+}
+
+/// Everything that makes two loads the same model: its kind, the SHA-256 of its
+/// weights, the device it runs on, the rest of the load config, which carries
+/// the backend, precision and settings baked into the graph, and the samples
+/// it was calibrated on. Loads with equal identities share one graph.
+#[derive(Debug, Clone, PartialEq)]
+struct ModelIdentity {
+    kind: ModelKind,
+    /// Models without a path embed their weights, so the kind identifies them.
+    weights: Option<[u8; 32]>,
+    wgpu_device: Option<WgpuDevice>,
+    config: GraphConfig,
+    /// SHA-256 of the calibration samples, if any.
+    samples: Option<[u8; 32]>,
+}
+
+/// Reads the wasi-nn graph builder array: `builders_len` pairs of `(ptr: u32, len: u32)`,
+/// which may sit at any alignment. Returns `None` for a negative or overflowing length.
+fn read_builders(memory: &Memory, builders_ptr: i32, builders_len: i32) -> Option<Vec<Vec<u8>>> {
+    let len = usize::try_from(builders_len).ok()?.checked_mul(2 * mem::size_of::<u32>())?;
+    let entries = read_pod::<u32>(get_slice!(memory, builders_ptr, len, u8))?;
+    Some(
+        entries
+            .chunks_exact(2)
+            .map(|entry| get_slice!(memory, entry[0], entry[1], u8).to_vec())
+            .collect(),
+    )
+}
+
+fn read_string(memory: &Memory, ptr: i32, len: i32) -> Option<String> {
+    let bytes = get_slice!(memory, ptr, len, u8);
+    std::str::from_utf8(bytes).ok().map(str::to_owned)
 }
 
 
 pub struct WasiNN {
     next_id: i32,
-    graphs: Mutex<HashMap<i32, GraphWithBackend>>,
+    /// Graph handles; handles of identical loads point to the same graph.
+    graphs: Mutex<HashMap<i32, Arc<GraphWithBackend>>>,
+    /// Loaded graphs by identity, alive while any handle references them.
+    models: Vec<(ModelIdentity, Weak<GraphWithBackend>)>,
+    weight_digests: WeightDigests,
     contexts: Mutex<HashMap<i32, (ContextWithBackend, i32)>>,
+    /// Fine-tuning sessions; each becomes a new graph when finished.
+    sessions: HashMap<i32, TrainingWithBackend>,
 }
 
 impl WasiNN {
@@ -53,14 +671,17 @@ impl WasiNN {
         WasiNN {
             next_id: 0,
             graphs: Mutex::new(HashMap::new()),
+            models: Vec::new(),
+            weight_digests: WeightDigests::default(),
             contexts: Mutex::new(HashMap::new()),
+            sessions: HashMap::new(),
         }
     }
 
     pub fn load<'a>(
         &mut self,
-        data_ptr: &i32,
-        data_len: &i32,
+        builders_ptr: &i32,
+        builders_len: &i32,
         encoding: &i32,
         target: &i32,
         graph_handle_ptr: &i32,
@@ -68,50 +689,200 @@ impl WasiNN {
     ) -> Result<Vec<WasmVal>, CoreError> {
         info!("WASI-NN Load called with encoding: {}, target: {}", encoding, target);
 
-        let bytes = memory
-            .data_pointer(*data_ptr as usize, *data_len as usize)
-            .unwrap();
-
-        // print for debugging
-        let name = String::from_utf8_lossy(&bytes);
-        debug!("Test bytes: {}", name);
-
         // must be burn encoding
-        if(*encoding != 8){
+        if *encoding != BURN_ENCODING {
             return Ok(vec![WasmVal::I32(ErrNo::InvalidEncoding as i32)]);
         }
 
-        // init graph; only squeezenet for now
-        let id = self.next_id;
-        self.next_id = id + 1;
+        let target = match Target::from_id(*target) {
+            Some(target) => target,
+            None => return Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)]),
+        };
 
-        // if target is gpu, only wgpu for now as backend
-        if(*target == 1) {
-            let device = WgpuDevice::DefaultDevice;
+        // the first builder may select the model, otherwise fall back to squeezenet;
+        // the rest are calibration samples of a quantized classifier
+        let builders = match read_builders(memory, *builders_ptr, *builders_len) {
+            Some(builders) => builders,
+            None => return Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)]),
+        };
+        let config = match builders.first().map(|bytes| GraphConfig::from_bytes(bytes)) {
+            Some(Ok(config)) => config.unwrap_or_default(),
+            Some(Err(err)) => return Ok(vec![WasmVal::I32(err as i32)]),
+            None => GraphConfig::default(),
+        };
+        debug!("Load config from builders: {:?}", config);
 
-            // 0:discrete, 1:integrated, 2:virtual, 3:cpu, 4:default
-            info!("Selected device: {:?}, {:?}", device, device.to_id());
+        let kind = config.kind.unwrap_or(ModelKind::Squeezenet);
+        let samples = builders.get(1..).unwrap_or_default();
+        self.load_graph(kind, &config, samples, target, graph_handle_ptr, memory)
+    }
 
-            let graph = Graph::Squeezenet(SqueezenetModel::<WgpuBackend>::new(&device));
-            self.graphs.lock().unwrap().insert(id, GraphWithBackend::WithWgpu(graph));
+    pub fn load_by_name<'a>(
+        &mut self,
+        name_ptr: &i32,
+        name_len: &i32,
+        graph_handle_ptr: &i32,
+        memory: &'a mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        let name = read_string(memory, *name_ptr, *name_len).unwrap_or_default();
+        info!("WASI-NN Load by name called with name: {}", name);
+
+        match ModelKind::from_name(&name) {
+            Some(kind) => self.load_graph(kind, &GraphConfig::default(), &[], Target::Cpu, graph_handle_ptr, memory),
+            None => Ok(vec![WasmVal::I32(ErrNo::NotFound as i32)]),
         }
+    }
 
-        else if(*target == 0) {
-            let device = NdArrayDevice::default();
-            info!("Selected device: {:?}, {:?}", device, device.to_id());
+    pub fn load_by_name_with_config<'a>(
+        &mut self,
+        name_ptr: &i32,
+        name_len: &i32,
+        config_ptr: &i32,
+        config_len: &i32,
+        graph_handle_ptr: &i32,
+        memory: &'a mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        let name = read_string(memory, *name_ptr, *name_len).unwrap_or_default();
+        let config_json = read_string(memory, *config_ptr, *config_len).unwrap_or_default();
+        info!("WASI-NN Load by name called with name: {}, config: {}", name, config_json);
 
-            let graph = Graph::Squeezenet(SqueezenetModel::<NdArrayBackend>::new(&device));
-            self.graphs.lock().unwrap().insert(id, GraphWithBackend::WithNdArray(graph));
-        }
+        let config: GraphConfig = if config_json.trim().is_empty() {
+            GraphConfig::default()
+        } else {
+            match serde_json::from_str(&config_json) {
+                Ok(config) => config,
+                Err(err) => {
+                    error!("Invalid load config: {}", err);
+                    return Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)]);
+                }
+            }
+        };
 
-        // unsupported target
-        else {
-            return Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)]);
+        // an explicit kind in the config wins over the name
+        let kind = match config.kind.or_else(|| ModelKind::from_name(&name)) {
+            Some(kind) => kind,
+            None => return Ok(vec![WasmVal::I32(ErrNo::NotFound as i32)]),
+        };
+
+        let target = config.target.unwrap_or(Target::Cpu);
+        self.load_graph(kind, &config, &[], target, graph_handle_ptr, memory)
+    }
+
+    fn load_graph<'a>(
+        &mut self,
+        kind: ModelKind,
+        config: &GraphConfig,
+        samples: &[Vec<u8>],
+        target: Target,
+        graph_handle_ptr: &i32,
+        memory: &'a mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        info!("Loading {:?} graph on {:?}", kind, target);
+
+        let fell_back = target == Target::Gpu && get_backends().adapters.is_empty();
+        let target = match target {
+            Target::Gpu if fell_back => match config.gpu_fallback {
+                GpuFallback::Cpu => {
+                    warn!("No wgpu adapter available, loading {:?} on the {:?} CPU backend instead", kind, config.cpu_backend);
+                    Target::Cpu
+                }
+                GpuFallback::Strict => {
+                    error!("No wgpu adapter available and the GPU fallback policy is strict");
+                    return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
+                }
+            },
+            target => target,
+        };
+
+        // if target is gpu, only wgpu for now as backend
+        let wgpu_device = match target {
+            Target::Gpu => match select_wgpu_device(&config.device) {
+                Some(device) => Some(device),
+                None => {
+                    error!("No wgpu adapter matches {:?}", config.device);
+                    return Ok(vec![WasmVal::I32(ErrNo::NotFound as i32)]);
+                }
+            },
+            Target::Cpu => None,
+        };
+
+        let weights = match config.path.as_ref().map(|path| self.weight_digests.digest(Path::new(path))).transpose() {
+            Ok(weights) => weights,
+            Err(err) => {
+                error!("Failed to read the weights of {:?}: {}", kind, err);
+                return Ok(vec![WasmVal::I32(ErrNo::NotFound as i32)]);
+            }
+        };
+        let identity = ModelIdentity {
+            kind,
+            weights,
+            wgpu_device: wgpu_device.clone(),
+            config: config.clone(),
+            samples: digest_samples(samples),
+        };
+
+        let shared = self.models
+            .iter()
+            .find(|(model, _)| *model == identity)
+            .and_then(|(_, graph)| graph.upgrade());
+        let graph = match shared {
+            Some(graph) => {
+                info!("Sharing the loaded {:?} model", kind);
+                graph
+            }
+            None => {
+                let graph = match build_graph(kind, config, samples, wgpu_device) {
+                    Ok(graph) => graph,
+                    Err(err) => {
+                        error!("Failed to load {:?} on {:?} with {:?} precision: {:?}", kind, target, config.precision, err);
+                        return Ok(vec![WasmVal::I32(err as i32)]);
+                    }
+                };
+
+                // a shared graph is warm already, only fresh builds need it
+                let passes = config.warmup.passes.unwrap_or(PLUGIN_CONFIG.warmup_passes);
+                if passes > 0 {
+                    let start = Instant::now();
+                    match with_graph!(&graph, graph => graph.warm_up(config, passes)) {
+                        Ok(()) => info!("Warmed up {:?} with {} passes in {:?}", kind, passes, start.elapsed()),
+                        Err(err) => warn!("Warm-up of {:?} failed: {:?}", kind, err),
+                    }
+                }
+
+                let graph = Arc::new(graph);
+                self.models.retain(|(_, model)| model.strong_count() > 0);
+                self.models.push((identity, Arc::downgrade(&graph)));
+                graph
+            }
+        };
+
+        let id = self.next_id;
+        self.next_id = id + 1;
+        if fell_back {
+            warn!("GPU target fell back to {} for graph handle {:?}", graph.backend_name(), id);
         }
+        info!("Created graph handle: {:?} on {} ({:?})", id, graph.backend_name(), graph.precision());
+        self.graphs.lock().unwrap().insert(id, graph);
 
         // write handle to pointer
         memory.write_data((*graph_handle_ptr as usize).into(), id);
-        info!("Created graph handle: {:?}", id);
+
+        Ok(vec![WasmVal::I32(ErrNo::Success as i32)])
+    }
+
+    /// Drops a graph handle and the contexts created from it. The model itself
+    /// is released with the last handle that shares it.
+    pub fn unload(&mut self, graph_handle: &i32) -> Result<Vec<WasmVal>, CoreError> {
+        let graph = match self.graphs.lock().unwrap().remove(graph_handle) {
+            Some(graph) => graph,
+            None => return Ok(vec![WasmVal::I32(ErrNo::NotFound as i32)]),
+        };
+        self.contexts.lock().unwrap().retain(|_, (_, handle)| *handle != *graph_handle);
+
+        let shared = Arc::strong_count(&graph) - 1;
+        drop(graph);
+        self.models.retain(|(_, model)| model.strong_count() > 0);
+        info!("Unloaded graph handle: {:?} ({} handles still share its model)", graph_handle, shared);
 
         Ok(vec![WasmVal::I32(ErrNo::Success as i32)])
     }
@@ -126,38 +897,36 @@ impl WasiNN {
         // check if graph handle exists
         if let Some(handle) = self.graphs.lock().unwrap().get(graph_handle) {
 
-            let id = self.next_id;
-            self.next_id = id + 1;
-
             // create context handle based on graph type
-            match handle {
+            let context = match handle.as_ref() {
                 GraphWithBackend::WithNdArray(graph) => {
-                    match graph {
-                        Graph::Squeezenet(_) => {
-                            let context = SqueezenetContext::<NdArrayBackend>::new();
-                            self.contexts.lock().unwrap().insert(id, (
-                                ContextWithBackend::WithNdArray(Context::Squeezenet(context)), *graph_handle
-                            ));
-                        }
-                        _ => {
-                            return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                        }
-                    }
+                    graph.init_execution_context().map(ContextWithBackend::WithNdArray)
                 }
                 GraphWithBackend::WithWgpu(graph) => {
-                    match graph {
-                        Graph::Squeezenet(_) => {
-                            let context = SqueezenetContext::<WgpuBackend>::new();
-                            self.contexts.lock().unwrap().insert(id, (
-                                ContextWithBackend::WithWgpu(Context::Squeezenet(context)), *graph_handle
-                            ));
-                        }
-                        _ => {
-                            return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                        }
-                    }
+                    graph.init_execution_context().map(ContextWithBackend::WithWgpu)
                 }
-            }
+                GraphWithBackend::WithCpu(graph) => {
+                    graph.init_execution_context().map(ContextWithBackend::WithCpu)
+                }
+                GraphWithBackend::WithWgpuF16(graph) => {
+                    graph.init_execution_context().map(ContextWithBackend::WithWgpuF16)
+                }
+                GraphWithBackend::WithWgpuBf16(graph) => {
+                    graph.init_execution_context().map(ContextWithBackend::WithWgpuBf16)
+                }
+                GraphWithBackend::WithNdArrayF64(graph) => {
+                    graph.init_execution_context().map(ContextWithBackend::WithNdArrayF64)
+                }
+            };
+
+            let context = match context {
+                Ok(context) => context,
+                Err(err) => return Ok(vec![WasmVal::I32(err as i32)]),
+            };
+
+            let id = self.next_id;
+            self.next_id = id + 1;
+            self.contexts.lock().unwrap().insert(id, (context, *graph_handle));
 
             // write handle to pointer
             memory.write_data((*ctx_handle_ptr as usize).into(), id);
@@ -180,69 +949,56 @@ impl WasiNN {
 
         match memory.get_data::<WasiTensorData>((*input_tensor_ptr as usize).into()) {
             Some(input_tensor) => {
-                let raw_dimensions = get_slice!(
+                let dimensions: Vec<usize> = match read_pod::<u32>(get_slice!(
                             memory,
                             input_tensor.dimens_ptr,
-                            INPUT_DIM * mem::size_of::<u32>(),
-                            u32
-                        );
-                let dimensions: [usize; 4] = raw_dimensions
-                    .iter()
-                    .map(|&x| x as usize)
-                    .collect::<Vec<usize>>()
-                    .try_into()
-                    .unwrap();
-
-
-                if(input_tensor.tensor_type != TensorType::F32) {
-                    return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                }
+                            input_tensor.dimens_length as usize * mem::size_of::<u32>(),
+                            u8
+                        )) {
+                    Some(dimensions) => dimensions.into_iter().map(|x| x as usize).collect(),
+                    None => return Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)]),
+                };
 
-                let tensor = get_slice!(
-                            memory,
-                            input_tensor.tensor_ptr,
-                            input_tensor.tensor_length,
-                            f32
-                        );
-
-                if let Some(handle) = self.contexts.lock().unwrap().get_mut(ctx_handle) {
-
-                    match handle {
-                        (ContextWithBackend::WithNdArray(context), _) => {
-                            match context {
-                                Context::Squeezenet(squeezenet_context) => {
-                                    if(*input_index != 0) {
-                                        return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                                    }
-                                    squeezenet_context.set_input(&tensor, dimensions);
-                                }
-                                _ => {
-                                    return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                                }
-                            }
-                        }
-                        (ContextWithBackend::WithWgpu(context), _) => {
-                            match context {
-                                Context::Squeezenet(squeezenet_context) => {
-                                    if(*input_index != 0) {
-                                        return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                                    }
-                                    squeezenet_context.set_input(&tensor, dimensions);
-                                }
-                                _ => {
-                                    return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                                }
-                            }
-                        }
+                let bytes = get_slice!(memory, input_tensor.tensor_ptr, input_tensor.tensor_length, u8);
+                let data = match input_tensor.tensor_type {
+                    TensorType::F32 => read_pod::<f32>(bytes).map(InputData::F32),
+                    TensorType::I64 => read_pod::<i64>(bytes).map(InputData::I64),
+                    TensorType::U8 => Some(InputData::U8(bytes)),
+                    TensorType::I32 => read_pod::<i32>(bytes)
+                        .map(|tensor| InputData::I64(tensor.into_iter().map(i64::from).collect())),
+                    _ => return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]),
+                };
+                let data = match data {
+                    Some(data) => data,
+                    None => {
+                        error!("Input of {} bytes is not a whole number of {:?} elements", bytes.len(), input_tensor.tensor_type);
+                        return Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)]);
                     }
+                };
+
+                let expected_len: usize = dimensions.iter().product();
+                let data_len = match &data {
+                    InputData::F32(tensor) => tensor.len(),
+                    InputData::I64(tensor) => tensor.len(),
+                    InputData::U8(tensor) => tensor.len(),
+                };
+                if data_len != expected_len {
+                    error!("Input length {} does not match dimensions {:?}", data_len, dimensions);
+                    return Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)]);
                 }
-                else {
-                    return Ok(vec![WasmVal::I32(ErrNo::NotFound as i32)]);
+
+                let result = if let Some((handle, _)) = self.contexts.lock().unwrap().get_mut(ctx_handle) {
+                    with_context!(handle, context => context.set_input(*input_index, data, &dimensions))
                 }
+                else {
+                    Err(ErrNo::NotFound)
+                };
 
-                debug!("Set input tensor context: {:?}[{:?}] : {:?} -> {:?} ...", ctx_handle, input_index, dimensions, &tensor[0..10]);
+                if result.is_ok() {
+                    debug!("Set input tensor context: {:?}[{:?}] : {:?}", ctx_handle, input_index, dimensions);
+                }
 
-                Ok(vec![WasmVal::I32(ErrNo::Success as i32)])
+                status(result)
             }
             None => Ok(vec![WasmVal::I32(ErrNo::MissingMemory as i32)]),
         }
@@ -253,42 +1009,40 @@ impl WasiNN {
         ctx_handle: &i32
     ) -> Result<Vec<WasmVal>, CoreError> {
 
-        if let Some(handle) = self.contexts.lock().unwrap().get_mut(ctx_handle) {
-            match handle {
-                (ContextWithBackend::WithNdArray(context), graphHandle) => {
-                    if let Some(graph) = self.graphs.lock().unwrap().get(graphHandle) {
-                        match (context, graph) {
-                            (Context::Squeezenet(squeezenet_context), GraphWithBackend::WithNdArray(Graph::Squeezenet(squeezenet_model))) => {
-                                let input_tensor = squeezenet_context.input.clone().unwrap();
-                                squeezenet_context.output = squeezenet_model.compute(input_tensor).into();
-                            }
-                            _ => {
-                                return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                            }
-                        }
-                    }
-                }
-                (ContextWithBackend::WithWgpu(context), graph_handle) => {
-                    if let Some(graph) = self.graphs.lock().unwrap().get(graph_handle) {
-                        match (context, graph) {
-                            (Context::Squeezenet(squeezenet_context), GraphWithBackend::WithWgpu(Graph::Squeezenet(squeezenet_model))) => {
-                                let input_tensor = squeezenet_context.input.clone().unwrap();
-                                squeezenet_context.output = squeezenet_model.compute(input_tensor).into();
-                            }
-                            _ => {
-                                return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                            }
-                        }
-                    }
-                }
-            }
-        } else {
-            return Ok(vec![WasmVal::I32(ErrNo::NotFound as i32)]);
+        let result = self.with_graph_of_context(ctx_handle, |context, graph| {
+            with_context_and_graph!(context, graph, context, graph => context.compute(graph))
+        });
+
+        if result.is_ok() {
+            info!("Computed context: {:?}", ctx_handle);
         }
 
-        info!("Computed context: {:?}", ctx_handle);
+        status(result)
+    }
 
-        Ok(vec![WasmVal::I32(ErrNo::Success as i32)])
+    pub fn compute_single<'a>(
+        &mut self,
+        ctx_handle: &i32
+    ) -> Result<Vec<WasmVal>, CoreError> {
+
+        let result = self.with_graph_of_context(ctx_handle, |context, graph| {
+            with_context_and_graph!(context, graph, context, graph => context.compute_single(graph))
+        });
+
+        status(result)
+    }
+
+    pub fn fini_single<'a>(
+        &mut self,
+        ctx_handle: &i32
+    ) -> Result<Vec<WasmVal>, CoreError> {
+
+        let result = match self.contexts.lock().unwrap().get_mut(ctx_handle) {
+            Some((handle, _)) => with_context!(handle, context => context.fini_single()),
+            None => Err(ErrNo::NotFound),
+        };
+
+        status(result)
     }
 
     pub fn get_output<'a>(
@@ -301,53 +1055,345 @@ impl WasiNN {
         memory: &'a mut Memory
     ) -> Result<Vec<WasmVal>, CoreError> {
 
-        let raw_output: Vec<f32>;
-        if let Some(handle) = self.contexts.lock().unwrap().get_mut(ctx_handle) {
-
-             match handle {
-                (ContextWithBackend::WithNdArray(context), _) => {
-                    match context {
-                        Context::Squeezenet(squeezenet_context) => {
-                            if(*output_index != 0) {
-                                return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                            }
-                            raw_output = squeezenet_context.get_output();
-
-                        }
-                        _ => {
-                            return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                        }
-                    }
-                }
-                (ContextWithBackend::WithWgpu(context), _) => {
-                    match context {
-                        Context::Squeezenet(squeezenet_context) => {
-                            if(*output_index != 0) {
-                                return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                            }
-                            raw_output = squeezenet_context.get_output();
-                        }
-                        _ => {
-                            return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
-                        }
-                    }
+        let output = match self.contexts.lock().unwrap().get_mut(ctx_handle) {
+            Some((handle, _)) => with_context!(handle, context => context.get_output(*output_index)),
+            None => Err(ErrNo::NotFound),
+        };
+
+        write_output(output, output_ptr, output_max_size, output_written_len_ptr, memory)
+    }
+
+    /// Writes the dimensions of an output as `u32`s and their count to `dims_written_ptr`.
+    pub fn get_output_shape(
+        &self,
+        ctx_handle: &i32,
+        output_index: &i32,
+        dims_ptr: &i32,
+        dims_max_len: &i32,
+        dims_written_ptr: &i32,
+        memory: &mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        let dims = match self.contexts.lock().unwrap().get_mut(ctx_handle) {
+            Some((handle, _)) => with_context!(handle, context => context.get_output_shape(*output_index)),
+            None => Err(ErrNo::NotFound),
+        };
+
+        let dims: Vec<u32> = match dims {
+            Ok(dims) => dims.into_iter().map(|dim| dim as u32).collect(),
+            Err(err) => return Ok(vec![WasmVal::I32(err as i32)]),
+        };
+        if dims.len() > *dims_max_len as usize {
+            return Ok(vec![WasmVal::I32(ErrNo::TooLarge as i32)]);
+        }
+
+        memory.write_bytes(bytemuck::cast_slice(&dims), *dims_ptr as u32).unwrap();
+        memory.write_data((*dims_written_ptr as usize).into(), dims.len() as u32);
+        Ok(vec![WasmVal::I32(ErrNo::Success as i32)])
+    }
+
+    pub fn get_output_single<'a>(
+        &mut self,
+        ctx_handle: &i32,
+        output_index: &i32,
+        output_ptr: &i32,
+        output_max_size: &i32,
+        output_written_len_ptr: &i32,
+        memory: &'a mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+
+        let output = match self.contexts.lock().unwrap().get_mut(ctx_handle) {
+            Some((handle, _)) => with_context!(handle, context => context.get_output_single(*output_index)),
+            None => Err(ErrNo::NotFound),
+        };
+
+        write_output(output, output_ptr, output_max_size, output_written_len_ptr, memory)
+    }
+
+    pub fn set_context_config<'a>(
+        &self,
+        ctx_handle: &i32,
+        config_ptr: &i32,
+        config_len: &i32,
+        memory: &'a mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        let json = match read_string(memory, *config_ptr, *config_len) {
+            Some(json) => json,
+            None => return Ok(vec![WasmVal::I32(ErrNo::InvalidEncoding as i32)]),
+        };
+
+        let result = match self.contexts.lock().unwrap().get_mut(ctx_handle) {
+            Some((handle, _)) => with_context!(handle, context => context.configure(&json)),
+            None => Err(ErrNo::NotFound),
+        };
+
+        if result.is_ok() {
+            info!("Configured context {:?}: {}", ctx_handle, json);
+        }
+
+        status(result)
+    }
+
+    /// Looks up a context together with the graph it was created from.
+    fn with_graph_of_context<F>(&self, ctx_handle: &i32, op: F) -> Result<(), ErrNo>
+    where
+        F: FnOnce(&mut ContextWithBackend, &GraphWithBackend) -> Result<(), ErrNo>,
+    {
+        let mut contexts = self.contexts.lock().unwrap();
+        let (context, graph_handle) = contexts.get_mut(ctx_handle).ok_or(ErrNo::NotFound)?;
+        let graphs = self.graphs.lock().unwrap();
+        let graph = graphs.get(graph_handle).ok_or(ErrNo::NotFound)?;
+        op(context, graph.as_ref())
+    }
+
+    /// Writes the backend report as JSON.
+    pub fn get_backend_report(
+        &self,
+        report_ptr: &i32,
+        report_max_size: &i32,
+        written_len_ptr: &i32,
+        memory: &mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        let report = serde_json::to_vec(get_backends()).map_err(|_| ErrNo::RuntimeError);
+        write_output(report, report_ptr, report_max_size, written_len_ptr, memory)
+    }
+
+    /// Writes the JSON metadata of a graph: the backend it actually runs on,
+    /// e.g. after a CPU fallback, and its float precision.
+    pub fn get_graph_metadata(
+        &self,
+        graph_handle: &i32,
+        metadata_ptr: &i32,
+        metadata_max_size: &i32,
+        written_len_ptr: &i32,
+        memory: &mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        let metadata = match self.graphs.lock().unwrap().get(graph_handle) {
+            Some(graph) => serde_json::to_vec(&graph.metadata()).map_err(|_| ErrNo::RuntimeError),
+            None => Err(ErrNo::NotFound),
+        };
+        write_output(metadata, metadata_ptr, metadata_max_size, written_len_ptr, memory)
+    }
+
+    /// Starts fine-tuning a copy of a classifier graph with the JSON training
+    /// config and writes the session handle.
+    pub fn create_training_session(
+        &mut self,
+        graph_handle: &i32,
+        config_ptr: &i32,
+        config_len: &i32,
+        session_handle_ptr: &i32,
+        memory: &mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        let json = read_string(memory, *config_ptr, *config_len).unwrap_or_default();
+        let config: TrainingConfig = if json.trim().is_empty() {
+            TrainingConfig::default()
+        } else {
+            match serde_json::from_str(&json) {
+                Ok(config) => config,
+                Err(err) => {
+                    error!("Invalid training config: {}", err);
+                    return Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)]);
                 }
             }
+        };
+
+        let session = match self.graphs.lock().unwrap().get(graph_handle).map(|graph| graph.as_ref()) {
+            Some(GraphWithBackend::WithWgpu(Graph::Classifier(graph))) => {
+                TrainingSession::new(graph, &config).map(TrainingWithBackend::WithWgpu)
+            }
+            Some(GraphWithBackend::WithNdArray(Graph::Classifier(graph))) => {
+                TrainingSession::new(graph, &config).map(TrainingWithBackend::WithNdArray)
+            }
+            Some(_) => Err(ErrNo::UnsupportedOperation),
+            None => Err(ErrNo::NotFound),
+        };
+        let session = match session {
+            Ok(session) => session,
+            Err(err) => return Ok(vec![WasmVal::I32(err as i32)]),
+        };
+
+        let id = self.next_id;
+        self.next_id = id + 1;
+        self.sessions.insert(id, session);
+        memory.write_data((*session_handle_ptr as usize).into(), id);
+        info!("Created training session handle: {:?} from graph {:?}", id, graph_handle);
+
+        Ok(vec![WasmVal::I32(ErrNo::Success as i32)])
+    }
+
+    /// Runs one optimizer step on an `F32` `[N, 3, H, W]` image batch and its `N`
+    /// `i64` class labels, writing the loss as an `f32`.
+    pub fn train_step(
+        &mut self,
+        session_handle: &i32,
+        input_tensor_ptr: &i32,
+        labels_ptr: &i32,
+        labels_len: &i32,
+        loss_ptr: &i32,
+        memory: &mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        let input_tensor = match memory.get_data::<WasiTensorData>((*input_tensor_ptr as usize).into()) {
+            Some(input_tensor) => input_tensor,
+            None => return Ok(vec![WasmVal::I32(ErrNo::MissingMemory as i32)]),
+        };
+        if !matches!(input_tensor.tensor_type, TensorType::F32) {
+            return Ok(vec![WasmVal::I32(ErrNo::UnsupportedOperation as i32)]);
+        }
+
+        let dimensions = read_pod::<u32>(get_slice!(
+            memory,
+            input_tensor.dimens_ptr,
+            input_tensor.dimens_length as usize * mem::size_of::<u32>(),
+            u8
+        ));
+        let dimensions: [usize; IMAGE_DIM] = match dimensions.map(|dimensions| dimensions.into_iter().map(|x| x as usize).collect::<Vec<_>>().try_into()) {
+            Some(Ok(dimensions)) => dimensions,
+            _ => return Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)]),
+        };
+        let images = match read_pod::<f32>(get_slice!(memory, input_tensor.tensor_ptr, input_tensor.tensor_length, u8)) {
+            Some(images) if images.len() == dimensions.iter().product::<usize>() => images,
+            _ => return Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)]),
+        };
+        let labels = match read_pod::<i64>(get_slice!(
+            memory,
+            *labels_ptr,
+            *labels_len as usize * mem::size_of::<i64>(),
+            u8
+        )) {
+            Some(labels) => labels,
+            None => return Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)]),
+        };
+
+        let loss = match self.sessions.get_mut(session_handle) {
+            Some(TrainingWithBackend::WithWgpu(session)) => session.step(&images, dimensions, &labels),
+            Some(TrainingWithBackend::WithNdArray(session)) => session.step(&images, dimensions, &labels),
+            None => Err(ErrNo::NotFound),
+        };
+
+        match loss {
+            Ok(loss) => {
+                memory.write_data((*loss_ptr as usize).into(), loss);
+                Ok(vec![WasmVal::I32(ErrNo::Success as i32)])
+            }
+            Err(err) => Ok(vec![WasmVal::I32(err as i32)]),
         }
-        else {
-            return Ok(vec![WasmVal::I32(ErrNo::NotFound as i32)]);
+    }
+
+    /// Ends a training session and writes the handle of a new graph holding the
+    /// trained weights.
+    pub fn finish_training(
+        &mut self,
+        session_handle: &i32,
+        graph_handle_ptr: &i32,
+        memory: &mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        let graph = match self.sessions.remove(session_handle) {
+            Some(TrainingWithBackend::WithWgpu(session)) => {
+                session.finish().map(|graph| GraphWithBackend::WithWgpu(Graph::Classifier(graph)))
+            }
+            Some(TrainingWithBackend::WithNdArray(session)) => {
+                session.finish().map(|graph| GraphWithBackend::WithNdArray(Graph::Classifier(graph)))
+            }
+            None => Err(ErrNo::NotFound),
+        };
+        let graph = match graph {
+            Ok(graph) => graph,
+            Err(err) => return Ok(vec![WasmVal::I32(err as i32)]),
+        };
+
+        // trained weights are unique to the session, so the graph bypasses the model cache
+        let id = self.next_id;
+        self.next_id = id + 1;
+        self.graphs.lock().unwrap().insert(id, Arc::new(graph));
+        memory.write_data((*graph_handle_ptr as usize).into(), id);
+        info!("Created graph handle: {:?} from training session {:?}", id, session_handle);
+
+        Ok(vec![WasmVal::I32(ErrNo::Success as i32)])
+    }
+
+    pub fn tokenize<'a>(
+        &self,
+        graph_handle: &i32,
+        text_ptr: &i32,
+        text_len: &i32,
+        ids_ptr: &i32,
+        mask_ptr: &i32,
+        max_tokens: &i32,
+        written_tokens_ptr: &i32,
+        memory: &'a mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        let text = match read_string(memory, *text_ptr, *text_len) {
+            Some(text) => text,
+            None => return Ok(vec![WasmVal::I32(ErrNo::InvalidEncoding as i32)]),
+        };
+
+        let encoded = match self.graphs.lock().unwrap().get(graph_handle) {
+            Some(graph) => match graph.tokenizer() {
+                Some(tokenizer) => tokenizer.encode(&text, usize::MAX),
+                None => Err(ErrNo::UnsupportedOperation),
+            },
+            None => Err(ErrNo::NotFound),
+        };
+
+        let (ids, mask) = match encoded {
+            Ok(encoded) => encoded,
+            Err(err) => return Ok(vec![WasmVal::I32(err as i32)]),
+        };
+
+        if ids.len() > *max_tokens as usize {
+            error!("Too many tokens: {} > {}", ids.len(), *max_tokens);
+            return Ok(vec![WasmVal::I32(ErrNo::TooLarge as i32)]);
         }
 
-        let output: &[u8] = bytemuck::cast_slice(&raw_output);
-        if output.len() > *output_max_size as usize {
-            error!("Was output too large: {} > {}", output.len(), *output_max_size as usize);
+        memory.write_bytes(bytemuck::cast_slice(&ids), *ids_ptr as u32).unwrap();
+        memory.write_bytes(bytemuck::cast_slice(&mask), *mask_ptr as u32).unwrap();
+        memory.write_data((*written_tokens_ptr as usize).into(), ids.len() as u32);
+        debug!("Tokenized {} bytes into {} tokens", text.len(), ids.len());
+
+        Ok(vec![WasmVal::I32(ErrNo::Success as i32)])
+    }
+
+    pub fn detokenize<'a>(
+        &self,
+        graph_handle: &i32,
+        ids_ptr: &i32,
+        ids_len: &i32,
+        text_ptr: &i32,
+        text_max_size: &i32,
+        written_len_ptr: &i32,
+        memory: &'a mut Memory
+    ) -> Result<Vec<WasmVal>, CoreError> {
+        let ids = match read_pod::<i64>(get_slice!(
+            memory,
+            *ids_ptr,
+            *ids_len as usize * mem::size_of::<i64>(),
+            u8
+        )) {
+            Some(ids) => ids,
+            None => return Ok(vec![WasmVal::I32(ErrNo::InvalidArgument as i32)]),
+        };
+
+        let decoded = match self.graphs.lock().unwrap().get(graph_handle) {
+            Some(graph) => match graph.tokenizer() {
+                Some(tokenizer) => tokenizer.decode(&ids),
+                None => Err(ErrNo::UnsupportedOperation),
+            },
+            None => Err(ErrNo::NotFound),
+        };
+
+        let text = match decoded {
+            Ok(text) => text,
+            Err(err) => return Ok(vec![WasmVal::I32(err as i32)]),
+        };
+
+        if text.len() > *text_max_size as usize {
+            error!("Decoded text too large: {} > {}", text.len(), *text_max_size);
             return Ok(vec![WasmVal::I32(ErrNo::TooLarge as i32)]);
         }
 
-        memory.write_bytes(output, *output_ptr as u32).unwrap();
-        memory.write_data((*output_written_len_ptr as usize).into(), output.len());
+        memory.write_bytes(text.as_bytes(), *text_ptr as u32).unwrap();
+        memory.write_data((*written_len_ptr as usize).into(), text.len() as u32);
 
         Ok(vec![WasmVal::I32(ErrNo::Success as i32)])
     }
 }
-
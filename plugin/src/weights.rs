@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use burn::prelude::Backend;
+use burn::record::{FullPrecisionSettings, NamedMpkFileRecorder, Record, Recorder, RecorderError};
+use burn_import::safetensors::{AdapterType, LoadArgs, SafetensorsFileRecorder};
+use log::debug;
+use sha2::{Digest, Sha256};
+
+/// Loads a `.safetensors` checkpoint into a burn record.
+///
+/// `remaps` are `(regex, replacement)` pairs applied in order to every key, used to
+/// translate the checkpoint's naming into the field names of our burn modules.
+pub fn load_safetensors<B: Backend, R: Record<B>>(
+    path: &Path,
+    remaps: &[(&str, &str)],
+    adapter: AdapterType,
+    device: &B::Device,
+) -> Result<R, RecorderError> {
+    debug!("Loading safetensors checkpoint: {}", path.display());
+
+    let mut args = LoadArgs::new(path.to_path_buf()).with_adapter_type(adapter);
+    for (pattern, replacement) in remaps {
+        args = args.with_key_remap(pattern, replacement);
+    }
+
+    SafetensorsFileRecorder::<FullPrecisionSettings>::default().load(args, device)
+}
+
+/// Loads a burn record file (`.mpk`) saved with `NamedMpkFileRecorder`, used for
+/// models we define ourselves rather than port from a HuggingFace checkpoint.
+pub fn load_record<B: Backend, R: Record<B>>(path: &Path, device: &B::Device) -> Result<R, RecorderError> {
+    debug!("Loading burn record: {}", path.display());
+    NamedMpkFileRecorder::<FullPrecisionSettings>::new().load(path.to_path_buf(), device)
+}
+
+/// SHA-256 of a model's weights, so loads of different weights never share a
+/// graph. Digests are reused while the size and modification time of every
+/// weight file stay the same.
+#[derive(Default)]
+pub struct WeightDigests {
+    digests: HashMap<PathBuf, (Vec<FileStamp>, [u8; 32])>,
+}
+
+/// Path, size and modification time of one weight file.
+type FileStamp = (PathBuf, u64, Option<SystemTime>);
+
+impl WeightDigests {
+    pub fn digest(&mut self, path: &Path) -> io::Result<[u8; 32]> {
+        let mut stamps = Vec::new();
+        stamp_files(path, &mut stamps)?;
+        if let Some((cached, digest)) = self.digests.get(path) {
+            if *cached == stamps {
+                return Ok(*digest);
+            }
+        }
+
+        let mut hasher = Sha256::new();
+        hash_weights(path, &mut hasher)?;
+        let digest: [u8; 32] = hasher.finalize().into();
+        self.digests.insert(path.to_path_buf(), (stamps, digest));
+        Ok(digest)
+    }
+}
+
+/// Lists the files below `path` in the order `hash_weights` reads them.
+fn stamp_files(path: &Path, stamps: &mut Vec<FileStamp>) -> io::Result<()> {
+    if path.is_file() {
+        let metadata = fs::metadata(path)?;
+        stamps.push((path.to_path_buf(), metadata.len(), metadata.modified().ok()));
+        return Ok(());
+    }
+    for entry in sorted_entries(path)? {
+        stamp_files(&entry, stamps)?;
+    }
+    Ok(())
+}
+
+fn sorted_entries(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+/// SHA-256 of the calibration samples of a load, so loads calibrated on different
+/// samples never share a graph. Every sample is prefixed by its length, which
+/// keeps the boundaries between them; `None` without samples.
+pub fn digest_samples(samples: &[Vec<u8>]) -> Option<[u8; 32]> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    for sample in samples {
+        hasher.update((sample.len() as u64).to_le_bytes());
+        hasher.update(sample);
+    }
+    Some(hasher.finalize().into())
+}
+
+/// Feeds the weights at `path` into `hasher`: the bytes of a file, or the relative
+/// names and bytes of every file below a directory, visited in sorted order.
+fn hash_weights(path: &Path, hasher: &mut Sha256) -> io::Result<()> {
+    if path.is_file() {
+        let mut file = File::open(path)?;
+        let mut buffer = vec![0u8; 1 << 20];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(());
+            }
+            hasher.update(&buffer[..read]);
+        }
+    }
+
+    for entry in sorted_entries(path)? {
+        if let Some(name) = entry.file_name() {
+            // length-prefixed, so a name cannot run into the bytes that follow
+            hasher.update((name.len() as u64).to_le_bytes());
+            hasher.update(name.as_encoded_bytes());
+        }
+        hash_weights(&entry, hasher)?;
+    }
+    Ok(())
+}